@@ -1,16 +1,119 @@
 use crate::compression;
-use crate::config::Config;
-use crate::hashing::{hash_file, HashRegistry};
+use crate::config::{self, Config, DeletionPolicy, Durability, Source, StorageLayout, SymlinkPolicy};
+use crate::hashing::{hash_file, FileStamp, FileState, HashRegistry, Journal, JournalEntry};
+use crate::metadata::FileMetadata;
+use crate::pack::{self, PackIndex, PackWriter};
+use crate::progress::Progress;
+use crate::report::{self, DryRunReport, FileFailure, FileRetry, FileWarning, Operation, RunReport, RunStatus};
+use crate::snapshot::{self, Snapshot, SnapshotEntry, SymlinkEntry, ATTIC_DIR};
+use crate::store::{self, StoredObject};
+use crate::throttle;
 use anyhow::{Context, Result};
-use indicatif::{ProgressBar, ProgressStyle};
 use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use std::collections::{hash_map, HashMap, HashSet};
+use std::fmt;
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
 use walkdir::WalkDir;
 
+/// Number of files backed up between checkpoints of the hash registry
+const CHECKPOINT_INTERVAL: usize = 1000;
+
+/// Longest time between checkpoints of the hash registry while files are being backed up
+const CHECKPOINT_PERIOD: Duration = Duration::from_secs(60);
+
+/// A file that was backed up, or failed to be, and the retries it took
+type FileOutcome = (PathBuf, Result<BackedUpFile>, Option<FileRetry>);
+
+/// A file that was stored in the destination
+struct BackedUpFile {
+    stored: StoredObject,
+    /// The file as it was before the stored copy was read
+    stamp: FileStamp,
+    metadata: FileMetadata,
+    /// Parts of the metadata that couldn't be read and were left out
+    metadata_problems: Vec<String>,
+    /// False if the file kept changing while it was read, so the copy may be torn
+    consistent: bool,
+}
+
+/// Outcome of walking the source and comparing it with the hash registry
+struct ScanResult {
+    /// Largest first, so the biggest files don't hold up the end of a run
+    files_to_process: Vec<PathBuf>,
+    deleted_files: Vec<PathBuf>,
+    /// Files whose content matches their last backup
+    unchanged_files: Vec<PathBuf>,
+    /// Files skipped because they match the blacklist
+    excluded_files: Vec<PathBuf>,
+    /// Size in bytes of each file to process, as seen during the walk
+    sizes: HashMap<PathBuf, u64>,
+    links: ScannedLinks,
+    /// Parts of the source that couldn't be walked and files that couldn't be hashed
+    failures: Vec<FileFailure>,
+}
+
+/// Links found by the scan, which are recorded in the snapshot instead of being stored
+#[derive(Default)]
+struct ScannedLinks {
+    /// Symlinks, with where they point
+    symlinks: Vec<(PathBuf, PathBuf)>,
+    /// Paths of files with several hard links, but the first, with the first path. Only the
+    /// first path is backed up.
+    hardlinks: Vec<(PathBuf, PathBuf)>,
+}
+
+/// Error returned by `run` and `resume` when they stop early because `stop` was set. The
+/// registry and a report of the partial run are saved before it is returned.
+#[derive(Debug)]
+pub struct Interrupted;
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Backup interrupted")
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+/// Error returned by `run` and `resume` when they finished, but some files couldn't be backed
+/// up or parts of the source couldn't be walked. The failures are listed in the run report at
+/// `report`.
+#[derive(Debug)]
+pub struct FilesFailed {
+    pub count: usize,
+    pub report: PathBuf,
+}
+
+impl fmt::Display for FilesFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Backup finished with {} failures, see {}",
+            self.count,
+            self.report.display()
+        )
+    }
+}
+
+impl std::error::Error for FilesFailed {}
+
+/// Most failures listed at the end of a run, the rest are only in the run report
+const FAILURES_SHOWN: usize = 20;
+
 pub struct BackupJob {
     pub config: Config,
     pub hash_registry: HashRegistry,
+    /// Re-hash every tracked file instead of trusting matching size and mtime
+    pub paranoid: bool,
+    /// Set from another thread, e.g. a signal handler, to stop the job once the files in
+    /// progress are finished
+    pub stop: Arc<AtomicBool>,
 }
 
 impl BackupJob {
@@ -18,54 +121,299 @@ impl BackupJob {
         Self {
             config,
             hash_registry,
+            paranoid: false,
+            stop: Arc::new(AtomicBool::new(false)),
         }
     }
 
-    /// Collects files that need to be processed, skipping blacklisted items and already processed files
-    fn collect_files_to_process(&self) -> Result<Vec<PathBuf>> {
-        let source_path = self
-            .config
-            .source_path
-            .as_ref()
-            .context("Source path not set")?;
+    fn is_stopping(&self) -> bool {
+        self.stop.load(Ordering::SeqCst)
+    }
+
+    /// Apply the configured transfer limit and scheduling priority. Worker threads started
+    /// afterwards inherit the lower priority.
+    fn apply_resource_limits(&self) -> Result<()> {
+        throttle::set_limit(self.config.max_bytes_per_second);
+        if self.config.low_priority {
+            throttle::lower_priority().context("Failed to lower the backup's priority")?;
+        }
+        Ok(())
+    }
+
+    /// Thread pool with the configured number of workers
+    fn worker_pool(&self) -> Result<ThreadPool> {
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(self.config.workers.unwrap_or(0))
+            .build()?;
+        Ok(pool)
+    }
+
+    /// Collects files that need to be processed, skipping blacklisted items and files whose
+    /// content still matches the hash recorded in the registry. Tracked files that are no
+    /// longer in the source are returned as deleted.
+    ///
+    /// Files whose size and mtime match the registry are assumed unchanged without being
+    /// hashed, unless the job is running in paranoid mode.
+    ///
+    /// Symlinks are preserved, followed or skipped as configured. A file with several hard
+    /// links is only backed up under the first of its paths in the walk, which is sorted by
+    /// name so it's the same path every run. Links aren't files of their own, so a tracked file
+    /// that has been replaced by a link counts as deleted.
+    ///
+    /// Files to process are ordered largest first. Starting the big ones early lets the small
+    /// ones fill in around them, instead of one worker grinding on a huge file found late in
+    /// the walk while the others sit idle.
+    fn scan_source(&mut self) -> Result<ScanResult> {
+        let sources = self.config.sources()?;
             
         let mut files_to_process = Vec::new();
+        let mut unchanged_files = Vec::new();
+        let mut excluded_files = Vec::new();
+        let mut sizes = HashMap::new();
+        let mut seen_files = HashSet::new();
+        let mut unreadable_paths = Vec::new();
+        let mut links = ScannedLinks::default();
+        // First path found of each file with several hard links, by device and inode
+        let mut first_links: HashMap<(u64, u64), PathBuf> = HashMap::new();
+        let mut failures = Vec::new();
 
-        for entry in WalkDir::new(source_path)
-            .follow_links(false)
-            .into_iter()
-            .filter_map(|e| e.ok())
-        {
+        let follow_links = self.config.symlinks == SymlinkPolicy::Follow;
+        let walks = sources.iter().flat_map(|source| {
+            WalkDir::new(&source.path)
+                .follow_links(follow_links)
+                .sort_by_file_name()
+                .into_iter()
+                .map(move |entry| (source, entry))
+        });
+        for (source, entry) in walks {
+            // An unfinished scan is discarded, so there is no point in going on
+            if self.is_stopping() {
+                break;
+            }
+
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) if follow_links && unfollowable_link(&e).is_some() => {
+                    let path = e.path().unwrap_or(&source.path);
+                    if config::source_for(&sources, path) == Some(source)
+                        && !self.config.is_excluded(source, path)
+                    {
+                        read_symlink(path, &mut links.symlinks, &mut failures);
+                    }
+                    continue;
+                }
+                Err(e) => {
+                    // Remember what couldn't be read so its files aren't taken for deletions
+                    let path = e.path().unwrap_or(&source.path).to_path_buf();
+                    eprintln!("Error walking {}: {}", path.display(), e);
+                    // The walk error repeats the path, so only keep what went wrong
+                    let error = match e.into_io_error() {
+                        Some(io_error) => io_error.into(),
+                        None => anyhow::anyhow!("File system loop found"),
+                    };
+                    unreadable_paths.push(path.clone());
+                    failures.push(FileFailure::new(path, Operation::Walk, &error));
+                    continue;
+                }
+            };
             let path = entry.path();
 
             // Skip directories (we'll create them as needed)
-            if path.is_dir() {
+            if entry.file_type().is_dir() {
+                continue;
+            }
+
+            // A source nested in another is walked twice, and the inner one owns its files
+            if config::source_for(&sources, path) != Some(source) {
+                continue;
+            }
+
+            // Only links that aren't followed are seen as links
+            if entry.file_type().is_symlink() {
+                if self.config.symlinks == SymlinkPolicy::Skip || self.config.is_excluded(source, path) {
+                    excluded_files.push(path.to_path_buf());
+                } else {
+                    read_symlink(path, &mut links.symlinks, &mut failures);
+                }
                 continue;
             }
 
+            seen_files.insert(path.to_path_buf());
+
             // Skip blacklisted paths
-            if self.config.is_blacklisted(path) {
+            if self.config.is_excluded(source, path) {
+                excluded_files.push(path.to_path_buf());
                 continue;
             }
 
-            // Skip already processed files (if hash exists)
-            if self.hash_registry.has_hash(path) {
+            let metadata = entry.metadata().ok();
+            let size = metadata.as_ref().map_or(0, |metadata| metadata.len());
+
+            if let Some(file_id) = metadata.as_ref().and_then(hardlink_id) {
+                match first_links.entry(file_id) {
+                    hash_map::Entry::Occupied(first) => {
+                        seen_files.remove(path);
+                        links.hardlinks.push((path.to_path_buf(), first.get().clone()));
+                        continue;
+                    }
+                    hash_map::Entry::Vacant(first) => {
+                        first.insert(path.to_path_buf());
+                    }
+                }
+            }
+
+            // Redo files an earlier run didn't finish, even if their content looks unchanged,
+            // since their stored copy may be missing or half-written
+            if self.hash_registry.get_state(path).is_some() {
+                files_to_process.push(path.to_path_buf());
+                sizes.insert(path.to_path_buf(), size);
                 continue;
             }
 
+            // Skip files whose content hasn't changed since the last backup
+            if let Some(recorded_hash) = self.hash_registry.get_hash(path) {
+                let stamp = metadata.as_ref().and_then(|metadata| FileStamp::from_metadata(metadata).ok());
+
+                // Fast path: size and mtime are unchanged
+                if !self.paranoid
+                    && stamp.is_some()
+                    && stamp == self.hash_registry.get_stamp(path)
+                {
+                    self.refresh_metadata(path, metadata.as_ref());
+                    unchanged_files.push(path.to_path_buf());
+                    continue;
+                }
+
+                match hash_file(path) {
+                    Ok(current_hash) if current_hash == recorded_hash => {
+                        // Content is the same, so remember the new stamp for the fast path
+                        if let Some(stamp) = stamp {
+                            self.hash_registry.set_stamp(path.to_path_buf(), stamp);
+                        }
+                        self.refresh_metadata(path, metadata.as_ref());
+                        unchanged_files.push(path.to_path_buf());
+                        continue;
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        eprintln!("Error hashing file {}: {:#}", path.display(), e);
+                        failures.push(FileFailure::new(path.to_path_buf(), Operation::Hash, &e));
+                        continue;
+                    }
+                }
+            }
+
             files_to_process.push(path.to_path_buf());
+            sizes.insert(path.to_path_buf(), size);
         }
+        files_to_process.sort_by(|a, b| sizes[b].cmp(&sizes[a]).then_with(|| a.cmp(b)));
+
+        let mut deleted_files: Vec<PathBuf> = self
+            .hash_registry
+            .tracked_paths()
+            .into_iter()
+            .filter(|path| config::source_for(&sources, path).is_some() && !seen_files.contains(path))
+            .filter(|path| !unreadable_paths.iter().any(|unreadable| path.starts_with(unreadable)))
+            .collect();
+        deleted_files.sort();
 
-        Ok(files_to_process)
+        Ok(ScanResult {
+            files_to_process,
+            deleted_files,
+            unchanged_files,
+            excluded_files,
+            sizes,
+            links,
+            failures,
+        })
+    }
+
+    /// Record the current metadata of a file whose content is unchanged, since changing its
+    /// permissions, owner or extended attributes leaves its mtime alone. Metadata that can't be
+    /// read in full is left as it was recorded.
+    fn refresh_metadata(&mut self, path: &Path, metadata: Option<&fs::Metadata>) {
+        let captured = metadata.and_then(|metadata| FileMetadata::capture(path, metadata).ok());
+        if let Some((file_metadata, problems)) = captured {
+            if problems.is_empty() {
+                self.hash_registry.set_metadata(path.to_path_buf(), file_metadata);
+            }
+        }
     }
 
-    /// Process a list of files with appropriate progress reporting
-    fn process_files(&mut self, files_to_process: Vec<PathBuf>, message: String) -> Result<()> {
-        let source_path = self
+    /// Tombstone files that were deleted from the source and apply the deletion policy to
+    /// their backed up copies, adding the copies that couldn't be handled to `report`
+    fn handle_deleted_files(&mut self, deleted_files: &[PathBuf], report: &mut RunReport) -> Result<()> {
+        if deleted_files.is_empty() {
+            return Ok(());
+        }
+
+        let journal = self.open_journal()?;
+        let sources = self.config.sources()?;
+        let destination_path = self
             .config
-            .source_path
+            .destination_path
             .as_ref()
-            .context("Source path not set")?;
+            .context("Destination path not set")?;
+
+        // Only the mirror layout keeps a copy per source file that can be moved or removed.
+        // Objects in the other layouts may be shared with other files, so they're kept.
+        let applies_policy = self.config.storage_layout == StorageLayout::Mirror;
+        if !applies_policy && self.config.deletion_policy != DeletionPolicy::Keep {
+            eprintln!(
+                "Warning: deletion_policy \"{}\" only applies to the mirror layout, the copies of deleted files are kept",
+                format!("{:?}", self.config.deletion_policy).to_lowercase()
+            );
+        }
+
+        println!("{} files deleted since the last backup:", deleted_files.len());
+        for path in deleted_files {
+            if let Some(tombstone) = self.hash_registry.mark_deleted(path) {
+                if let Some(journal) = &journal {
+                    journal.append(&JournalEntry::Deleted {
+                        path: path.clone(),
+                        tombstone,
+                    })?;
+                }
+            }
+            println!("  {}", path.display());
+            if !applies_policy {
+                continue;
+            }
+
+            let result = config::source_for(&sources, path)
+                .context("File is outside every source")
+                .and_then(|source| {
+                    let hash = self
+                        .hash_registry
+                        .last_known_hash(path)
+                        .context("Deleted file has no recorded hash")?;
+                    apply_deletion_policy(self.config.deletion_policy, path, source, destination_path, &hash)
+                });
+            if let Err(e) = result {
+                eprintln!("Error handling deleted file {}: {:#}", path.display(), e);
+                report.failures.push(FileFailure::new(path.clone(), Operation::Write, &e));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Process a list of files with appropriate progress reporting. Workers take files in
+    /// order off the largest-first list, each finished file is journaled as soon as its object
+    /// is on disk, and the registry is checkpointed every `CHECKPOINT_INTERVAL` files or
+    /// `CHECKPOINT_PERIOD`.
+    ///
+    /// Once `stop` is set no new files are started, and the files that were never started are
+    /// counted as remaining in `report`.
+    fn process_files(
+        &mut self,
+        files_to_process: Vec<PathBuf>,
+        sizes: &HashMap<PathBuf, u64>,
+        message: String,
+        report: &mut RunReport,
+    ) -> Result<()> {
+        let journal = self.open_journal()?;
+        let sources = self.config.sources()?;
         let destination_path = self
             .config
             .destination_path
@@ -75,79 +423,818 @@ impl BackupJob {
         // Create destination directory if it doesn't exist
         fs::create_dir_all(destination_path)?;
 
-        // Set up progress bar
-        let pb = ProgressBar::new(files_to_process.len() as u64);
-        pb.set_style(
-            ProgressStyle::default_bar()
-                .template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {pos}/{len} ({eta})")
-                .unwrap()
-                .progress_chars("#>-"),
+        // Persist the planned work so an interrupted run knows what it never got to
+        self.save_registry()?;
+
+        // Track progress in bytes read, so a large file doesn't throw off the ETA
+        let size_of = |path: &Path| sizes.get(path).copied().unwrap_or(0);
+        let progress = Progress::new(
+            files_to_process.iter().map(|path| size_of(path)).sum(),
+            files_to_process.len(),
         );
 
         // Create thread-safe clones to share between threads
-        let source_path = source_path.clone();
         let destination_path = destination_path.clone();
-        
-        // Process files in parallel using Rayon
-        files_to_process.par_iter().for_each(|source_file| {
-            let result = process_file(
-                source_file,
-                &source_path,
+
+        // Workers append small files to shared pack files in the packed layout
+        let packs = match self.config.storage_layout {
+            StorageLayout::Packed => Some(PackWriter::open(
                 &destination_path,
-            );
-            
-            if let Ok(hash) = result {
-                // Safe to mutate our own hash registry here
-                let mut registry_lock = self.hash_registry.hashes.lock().unwrap();
-                registry_lock.insert(source_file.to_path_buf(), hash);
-            } else if let Err(e) = result {
-                eprintln!("Error processing file {}: {}", source_file.display(), e);
+                self.config.pack_target_size,
+                self.config.durability,
+            )?),
+            _ => None,
+        };
+
+        let back_up = |source_file: &Path| -> FileOutcome {
+            let (result, retry) = self.with_retries(source_file, || {
+                // In memory first, so a checkpoint in between still has it in progress
+                self.hash_registry.mark_started(source_file.to_path_buf());
+                if let Some(journal) = &journal {
+                    journal
+                        .append(&JournalEntry::Started {
+                            path: source_file.to_path_buf(),
+                        })
+                        .context(Operation::Write)?;
+                }
+                let file = self.store_unchanged(source_file, || {
+                    progress.track(source_file, size_of(source_file), || {
+                        let source = config::source_for(&sources, source_file)
+                            .context("File is outside every source")
+                            .context(Operation::Read)?;
+                        // Steps that read the source tag their errors, the rest write to the
+                        // destination
+                        self.store_file(source_file, source, &destination_path, packs.as_ref())
+                            .map_err(|e| Operation::Write.tag_untagged(e))
+                    })
+                })?;
+                Ok(file)
+            });
+
+            progress.file_done();
+
+            (source_file.to_path_buf(), result, retry)
+        };
+
+        // Save the registry with everything recorded so far
+        let checkpoint = || -> Result<()> {
+            match &journal {
+                Some(journal) => journal.pause(|| self.save_registry()),
+                None => self.save_registry(),
+            }
+        };
+
+        // Every worker takes the next file off the one largest-first list, so the biggest files
+        // are always the ones started first
+        let next = AtomicUsize::new(0);
+        let started = AtomicUsize::new(0);
+        let next_file = || {
+            if self.is_stopping() {
+                return None;
+            }
+            let file = files_to_process.get(next.fetch_add(1, Ordering::SeqCst))?;
+            started.fetch_add(1, Ordering::SeqCst);
+            Some(file)
+        };
+
+        let pool = self.worker_pool()?;
+        let (sender, receiver) = mpsc::channel::<FileOutcome>();
+        let mut deduplicated = 0;
+        pool.in_place_scope(|scope| -> Result<()> {
+            for _ in 0..pool.current_num_threads() {
+                let sender = sender.clone();
+                let (back_up, next_file) = (&back_up, &next_file);
+                scope.spawn(move |_| {
+                    while let Some(source_file) = next_file() {
+                        // The receiver is gone if recording a file failed, so stop there
+                        if sender.send(back_up(source_file)).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+            drop(sender);
+
+            // Record the new hashes as files finish, replacing entries for files that changed,
+            // and count the failures against their files. The registry is checkpointed every
+            // so many files or so often, without waiting for the files still in progress.
+            let mut since_checkpoint = 0;
+            let mut last_checkpoint = Instant::now();
+            while let Ok(outcome) = receiver.recv() {
+                // Take every file that has finished meanwhile, so one sync of the packs covers
+                // them all. Packed objects must be on disk before the journal or the registry
+                // refers to them.
+                let outcomes: Vec<FileOutcome> = std::iter::once(outcome).chain(receiver.try_iter()).collect();
+                if let Some(packs) = &packs {
+                    packs.sync()?;
+                }
+
+                since_checkpoint += outcomes.len();
+                for (path, result, retry) in outcomes {
+                    report.retries.extend(retry);
+                    match result {
+                        Ok(file) => {
+                            if let Some(journal) = &journal {
+                                journal.append(&JournalEntry::Stored {
+                                    path: path.clone(),
+                                    hash: file.stored.hash.clone(),
+                                    stamp: file.stamp,
+                                    metadata: Some(file.metadata.clone()),
+                                })?;
+                                if !file.consistent {
+                                    journal.append(&JournalEntry::Inconsistent { path: path.clone() })?;
+                                }
+                            }
+                            if !file.stored.newly_stored {
+                                deduplicated += 1;
+                            }
+                            report.files_processed += 1;
+                            for problem in file.metadata_problems {
+                                eprintln!("Warning for {}: {}", path.display(), problem);
+                                report.warnings.push(FileWarning {
+                                    path: path.clone(),
+                                    warning: problem,
+                                });
+                            }
+                            self.hash_registry.set_stamp(path.clone(), file.stamp);
+                            self.hash_registry.set_metadata(path.clone(), file.metadata);
+                            self.hash_registry.set_hash(path.clone(), file.stored.hash);
+                            if !file.consistent {
+                                report.inconsistent_files.push(path.clone());
+                                self.hash_registry.mark_inconsistent(path);
+                            }
+                        }
+                        Err(e) => {
+                            eprintln!("Error processing file {}: {:#}", path.display(), e);
+                            // Every step of backing up a file tags its errors
+                            let operation = Operation::of(&e).unwrap_or(Operation::Write);
+                            report.failures.push(FileFailure::new(path.clone(), operation, &e));
+                            let error = format!("{:#}", e);
+                            if let Some(journal) = &journal {
+                                journal.append(&JournalEntry::Failed {
+                                    path: path.clone(),
+                                    error: error.clone(),
+                                })?;
+                            }
+                            self.hash_registry.record_failure(path, error);
+                        }
+                    }
+                }
+
+                if since_checkpoint >= CHECKPOINT_INTERVAL || last_checkpoint.elapsed() >= CHECKPOINT_PERIOD {
+                    checkpoint()?;
+                    since_checkpoint = 0;
+                    last_checkpoint = Instant::now();
+                }
+            }
+            Ok(())
+        })?;
+        checkpoint()?;
+
+        report.files_remaining = files_to_process.len() - started.into_inner();
+        if self.is_stopping() {
+            progress.abandon();
+        } else {
+            progress.finish(message);
+        }
+
+        if deduplicated > 0 {
+            println!("{} files matched content that was already stored", deduplicated);
+        }
+
+        Ok(())
+    }
+
+    /// Store `source_file` with `store`, comparing its size and mtime before and after. A file
+    /// that changed while it was read is stored again, up to `changed_file_retries` times,
+    /// before its last copy is kept as inconsistent. Its metadata is captured along with the
+    /// stamp before each read.
+    fn store_unchanged(
+        &self,
+        source_file: &Path,
+        mut store: impl FnMut() -> Result<StoredObject>,
+    ) -> Result<BackedUpFile> {
+        // Stamp before reading so a write during compression is caught next run
+        let mut stamp = FileStamp::from_path(source_file).context(Operation::Read)?;
+        let (mut metadata, mut metadata_problems) = FileMetadata::from_path(source_file).context(Operation::Read)?;
+        let mut rereads = 0;
+        loop {
+            let stored = store()?;
+            let stamp_after = FileStamp::from_path(source_file).context(Operation::Read)?;
+            let consistent = stamp_after == stamp;
+            if consistent || rereads >= self.config.changed_file_retries || self.is_stopping() {
+                return Ok(BackedUpFile {
+                    stored,
+                    stamp,
+                    metadata,
+                    metadata_problems,
+                    consistent,
+                });
+            }
+
+            eprintln!("{} changed while it was read, reading it again", source_file.display());
+            stamp = stamp_after;
+            (metadata, metadata_problems) = FileMetadata::from_path(source_file).context(Operation::Read)?;
+            rereads += 1;
+        }
+    }
+
+    /// Run `backup` for `path`, trying again after a pause while it fails with an error the
+    /// retry policy counts as transient. Returns its result and, if it was retried, a record
+    /// of the retries for the run report.
+    fn with_retries<T>(
+        &self,
+        path: &Path,
+        mut backup: impl FnMut() -> Result<T>,
+    ) -> (Result<T>, Option<FileRetry>) {
+        let policy = &self.config.retry;
+        let mut attempts = 1;
+        let mut last_retried = None;
+        let result = loop {
+            let error = match backup() {
+                Ok(value) => break Ok(value),
+                Err(e) => e,
+            };
+
+            let kind = report::error_kind(&error);
+            if !policy.is_retryable(&kind) || attempts >= policy.attempts || self.is_stopping() {
+                break Err(error);
             }
+            eprintln!(
+                "Retrying {} after a transient error ({}): {:#}",
+                path.display(),
+                kind,
+                error
+            );
+            last_retried = Some((kind, format!("{:#}", error)));
+            thread::sleep(policy.backoff(attempts));
+            attempts += 1;
+        };
 
-            pb.inc(1);
+        let retry = last_retried.map(|(kind, error)| FileRetry {
+            path: path.to_path_buf(),
+            attempts,
+            kind,
+            error,
+            recovered: result.is_ok(),
         });
+        (result, retry)
+    }
+
+    /// Compress a single file into the destination using the configured storage layout
+    fn store_file(
+        &self,
+        source_file: &Path,
+        source: &Source,
+        destination_root: &Path,
+        packs: Option<&PackWriter>,
+    ) -> Result<StoredObject> {
+        let durability = self.config.durability;
+        match self.config.storage_layout {
+            StorageLayout::Mirror => {
+                if let Some(previous_hash) = self.hash_registry.last_known_hash(source_file) {
+                    retire_previous_version(
+                        source_file,
+                        source,
+                        destination_root,
+                        &previous_hash,
+                    )?;
+                }
+                let hash = process_file(
+                    source_file,
+                    &source.path,
+                    &destination_root.join(&source.prefix),
+                    durability,
+                )?;
+                Ok(StoredObject {
+                    hash,
+                    newly_stored: true,
+                })
+            }
+            StorageLayout::Objects => store::store_object(source_file, destination_root, durability),
+            StorageLayout::Chunked => {
+                if fs::metadata(source_file).context(Operation::Read)?.len() >= store::CHUNKING_THRESHOLD {
+                    store::store_chunked(source_file, destination_root, durability)
+                } else {
+                    store::store_object(source_file, destination_root, durability)
+                }
+            }
+            StorageLayout::Packed => {
+                let packs = packs.context("Pack writer not open")?;
+                if fs::metadata(source_file).context(Operation::Read)?.len() <= store::PACKING_THRESHOLD {
+                    store::store_packed(source_file, destination_root, packs)
+                } else {
+                    store::store_object(source_file, destination_root, durability)
+                }
+            }
+        }
+    }
 
-        pb.finish_with_message(message);
+    /// Open the journal next to the configured hash file, if any
+    fn open_journal(&self) -> Result<Option<Journal>> {
+        match &self.config.hash_file_path {
+            Some(hash_file_path) => Ok(Some(Journal::open(hash_file_path, self.config.durability)?)),
+            None => Ok(None),
+        }
+    }
 
-        // Save the updated hash registry
+    /// Checkpoint the hash registry to the configured hash file, if any
+    fn save_registry(&self) -> Result<()> {
         if let Some(hash_file_path) = &self.config.hash_file_path {
-            self.hash_registry.save_to_file(hash_file_path)?;
+            self.hash_registry.checkpoint(hash_file_path)?;
+        }
+
+        Ok(())
+    }
+
+    /// Write a snapshot manifest listing every backed up file and the object it's stored in,
+    /// along with the `links` found by the scan. Hard links to a file that has never been backed
+    /// up are left out and added to `report` as failures.
+    fn write_snapshot(&self, links: &ScannedLinks, report: &mut RunReport) -> Result<Snapshot> {
+        let sources = self.config.sources()?;
+        let destination_path = self
+            .config
+            .destination_path
+            .as_ref()
+            .context("Destination path not set")?;
+
+        // Unchanged files keep pointing at the objects they were stored in, even if the storage
+        // layout has changed since
+        let previous_objects: HashMap<PathBuf, SnapshotEntry> =
+            match snapshot::find_snapshot(destination_path, None) {
+                Ok(previous) => previous
+                    .files
+                    .into_iter()
+                    .map(|entry| (entry.path.clone(), entry))
+                    .collect(),
+                Err(_) => HashMap::new(),
+            };
+
+        let pack_index = PackIndex::load(destination_path)?;
+
+        // The other hard links of each file that has them, with their paths in the backup
+        let mut hardlinks: HashMap<&Path, Vec<(&Path, PathBuf)>> = HashMap::new();
+        for (path, first) in &links.hardlinks {
+            if let Some(source) = config::source_for(&sources, path) {
+                let relative_path = source.prefix.join(path.strip_prefix(&source.path)?);
+                hardlinks.entry(first.as_path()).or_default().push((path.as_path(), relative_path));
+            }
+        }
+
+        let mut snapshot = Snapshot::new(sources.clone());
+        for path in self.hash_registry.tracked_paths() {
+            let source = match config::source_for(&sources, &path) {
+                Some(source) => source,
+                None => continue,
+            };
+
+            let hash = match self.hash_registry.get_hash(&path) {
+                Some(hash) => hash,
+                None => continue,
+            };
+            let size = self.hash_registry.get_stamp(&path).map_or(0, |stamp| stamp.size);
+            let relative_path = source.prefix.join(path.strip_prefix(&source.path)?);
+            let (object, chunked, packed) = match previous_objects.get(&relative_path) {
+                Some(previous) if previous.hash == hash => {
+                    (previous.object.clone(), previous.chunked, previous.packed)
+                }
+                _ => match self.config.storage_layout {
+                    StorageLayout::Mirror => {
+                        (destination_path_for(&path, &source.path, &source.prefix)?, false, false)
+                    }
+                    StorageLayout::Objects => (store::object_path(&hash), false, false),
+                    StorageLayout::Chunked => {
+                        let chunk_list = store::chunk_list_path(&hash);
+                        if destination_path.join(&chunk_list).exists() {
+                            (chunk_list, true, false)
+                        } else {
+                            (store::object_path(&hash), false, false)
+                        }
+                    }
+                    StorageLayout::Packed => match pack_index.get(&hash) {
+                        Some(location) => (pack::pack_path(&location.pack), false, true),
+                        None => (store::object_path(&hash), false, false),
+                    },
+                },
+            };
+            let mut links: Vec<PathBuf> = hardlinks
+                .remove(path.as_path())
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(_, relative_path)| relative_path)
+                .collect();
+            links.sort();
+            snapshot.files.push(SnapshotEntry {
+                path: relative_path,
+                hash,
+                size,
+                object,
+                chunked,
+                packed,
+                links,
+                metadata: self.hash_registry.get_metadata(&path),
+            });
+        }
+        snapshot.files.sort_by(|a, b| a.path.cmp(&b.path));
+
+        // The content of these links is only stored under the first path, which has never been
+        // backed up, so they're missing from the snapshot
+        for (first, group) in hardlinks {
+            let operation = report
+                .failures
+                .iter()
+                .find(|failure| failure.path == first)
+                .map_or(Operation::Write, |failure| failure.operation);
+            for (path, _) in group {
+                let error = anyhow::anyhow!("Hard link to {}, which couldn't be backed up", first.display());
+                report.failures.push(FileFailure::new(path.to_path_buf(), operation, &error));
+            }
+        }
+
+        for (path, target) in &links.symlinks {
+            if let Some(source) = config::source_for(&sources, path) {
+                snapshot.symlinks.push(SymlinkEntry {
+                    path: source.prefix.join(path.strip_prefix(&source.path)?),
+                    target: target.clone(),
+                });
+            }
+        }
+        snapshot.symlinks.sort_by(|a, b| a.path.cmp(&b.path));
+
+        fs::create_dir_all(destination_path)?;
+        snapshot.save(destination_path, self.config.durability)?;
+        Ok(snapshot)
+    }
+
+    /// Save the registry and record the state of the backup as a new snapshot. If any files
+    /// failed, they are summarised and `FilesFailed` is returned.
+    fn finish_run(&self, mut report: RunReport, links: &ScannedLinks) -> Result<()> {
+        self.save_registry()?;
+        let snapshot = self.write_snapshot(links, &mut report)?;
+        println!(
+            "Created snapshot {} with {} files",
+            snapshot.id,
+            snapshot.files.len()
+        );
+
+        let destination_path = self
+            .config
+            .destination_path
+            .as_ref()
+            .context("Destination path not set")?;
+        let report_path = report.finish(RunStatus::Completed, destination_path, self.config.durability)?;
+        if !report.inconsistent_files.is_empty() {
+            println!(
+                "{} files kept changing while they were read, so their copies may be torn. They are backed up again next run:",
+                report.inconsistent_files.len()
+            );
+            for path in &report.inconsistent_files {
+                println!("  {}", path.display());
+            }
+        }
+        if !report.warnings.is_empty() {
+            println!(
+                "{} parts of file metadata couldn't be read and were left out, see {}",
+                report.warnings.len(),
+                report_path.display()
+            );
+        }
+        if !report.retries.is_empty() {
+            let recovered = report.retries.iter().filter(|retry| retry.recovered).count();
+            println!(
+                "Retried {} files after transient errors, {} of them succeeded",
+                report.retries.len(),
+                recovered
+            );
+        }
+        if report.failures.is_empty() {
+            return Ok(());
+        }
+
+        describe_failures(&report.failures);
+        Err(FilesFailed {
+            count: report.failures.len(),
+            report: report_path,
+        }
+        .into())
+    }
+
+    /// Save the progress of a run that was asked to stop and report it as interrupted. No
+    /// snapshot is taken, since the backup doesn't describe a single point in time yet.
+    fn stop_run(&self, mut report: RunReport) -> Result<()> {
+        self.save_registry()?;
+        if let Some(destination_path) = &self.config.destination_path {
+            report.finish(RunStatus::Interrupted, destination_path, self.config.durability)?;
+        }
+        println!(
+            "Stopped after {} files, {} files remaining",
+            report.files_processed, report.files_remaining
+        );
+        Err(Interrupted.into())
+    }
+
+    /// Delete temporary files an interrupted run left in the destination, except the partial
+    /// files of large `files_to_process` that can be resumed
+    fn remove_leftover_temp_files(&self, files_to_process: &[PathBuf], sizes: &HashMap<PathBuf, u64>) -> Result<()> {
+        if let Some(destination_path) = &self.config.destination_path {
+            let resumable = self.resumable_partials(files_to_process, sizes, destination_path)?;
+            let removed = compression::remove_temp_files(destination_path, &resumable)?;
+            if removed > 0 {
+                println!("Removed {} unfinished files left by an interrupted run", removed);
+            }
         }
 
         Ok(())
     }
 
+    /// Partial files that storing the large ones among `files_to_process` would resume
+    fn resumable_partials(
+        &self,
+        files_to_process: &[PathBuf],
+        sizes: &HashMap<PathBuf, u64>,
+        destination_root: &Path,
+    ) -> Result<HashSet<PathBuf>> {
+        let sources = self.config.sources()?;
+        let mut partials = HashSet::new();
+        for path in files_to_process {
+            if sizes.get(path).is_none_or(|&size| size < compression::RESUMABLE_THRESHOLD) {
+                continue;
+            }
+            let destination = match self.config.storage_layout {
+                StorageLayout::Mirror => {
+                    let Some(source) = config::source_for(&sources, path) else {
+                        continue;
+                    };
+                    destination_path_for(path, &source.path, &destination_root.join(&source.prefix))?
+                }
+                StorageLayout::Objects | StorageLayout::Packed => store::incoming_path(path, destination_root),
+                // Files this large are always chunked
+                StorageLayout::Chunked => continue,
+            };
+            partials.insert(compression::partial_path_for(&destination));
+        }
+        Ok(partials)
+    }
+
+    /// Work out what `run` would do without touching the destination or saving the registry
+    pub fn dry_run(&mut self) -> Result<DryRunReport> {
+        self.apply_resource_limits()?;
+        let scan = self.scan_source()?;
+
+        let mut new_files = Vec::new();
+        let mut changed_files = Vec::new();
+        let mut retried_files = Vec::new();
+        for path in &scan.files_to_process {
+            match self.hash_registry.get_hash(path) {
+                None => new_files.push(path.clone()),
+                // Queued only because an earlier run didn't finish it, not because it changed
+                Some(recorded_hash)
+                    if self.hash_registry.get_state(path).is_some()
+                        && self.matches_backup(path, &recorded_hash) =>
+                {
+                    retried_files.push(path.clone())
+                }
+                Some(_) => changed_files.push(path.clone()),
+            }
+        }
+
+        let total_bytes = scan.sizes.values().sum();
+        let estimated_compressed_bytes = self.worker_pool()?.install(|| {
+            scan.sizes
+                .par_iter()
+                .map(|(path, &size)| compression::estimate_compressed_size(path, size).unwrap_or(size))
+                .sum()
+        });
+
+        let mut report = DryRunReport {
+            new_files,
+            changed_files,
+            retried_files,
+            unchanged_files: scan.unchanged_files,
+            excluded_files: scan.excluded_files,
+            deleted_files: scan.deleted_files,
+            total_bytes,
+            estimated_compressed_bytes,
+            failures: scan.failures,
+        };
+        for files in [
+            &mut report.new_files,
+            &mut report.changed_files,
+            &mut report.retried_files,
+            &mut report.unchanged_files,
+            &mut report.excluded_files,
+        ] {
+            files.sort();
+        }
+
+        Ok(report)
+    }
+
+    /// Whether `path` still holds the content last backed up as `recorded_hash`, trusting an
+    /// unchanged size and modification time unless paranoid
+    fn matches_backup(&self, path: &Path, recorded_hash: &str) -> bool {
+        if !self.paranoid {
+            let stamp = FileStamp::from_path(path).ok();
+            if stamp.is_some() && stamp == self.hash_registry.get_stamp(path) {
+                return true;
+            }
+        }
+        hash_file(path).is_ok_and(|current_hash| current_hash == recorded_hash)
+    }
+
     /// Run a full backup operation
     pub fn run(&mut self) -> Result<()> {
-        let files_to_process = self.collect_files_to_process()?;
+        let mut report = RunReport::start();
+        self.apply_resource_limits()?;
+        let scan = self.scan_source()?;
+        if self.is_stopping() {
+            return self.stop_run(report);
+        }
+        self.remove_leftover_temp_files(&scan.files_to_process, &scan.sizes)?;
+        report.failures.extend(scan.failures);
+        self.handle_deleted_files(&scan.deleted_files, &mut report)?;
+        let files_to_process = scan.files_to_process;
+        self.hash_registry.plan(&files_to_process);
         
         if files_to_process.is_empty() {
-            println!("No files to backup. Everything is already up to date.");
-            return Ok(());
+            println!(
+                "No files to backup. All {} tracked files are up to date.",
+                self.hash_registry.len()
+            );
+        } else {
+            let changed_count = files_to_process
+                .iter()
+                .filter(|path| self.hash_registry.has_hash(path))
+                .count();
+            println!(
+                "Backing up {} new and {} changed files",
+                files_to_process.len() - changed_count,
+                changed_count
+            );
+
+            self.process_files(
+                files_to_process,
+                &scan.sizes,
+                "Backup completed".to_string(),
+                &mut report,
+            )?;
+            if self.is_stopping() {
+                return self.stop_run(report);
+            }
         }
-        
-        self.process_files(files_to_process, "Backup completed".to_string())
+
+        self.finish_run(report, &scan.links)
     }
     
-    /// Resume a previously interrupted backup
+    /// Print what earlier runs left unfinished
+    fn describe_unfinished_files(&self) {
+        let states = self.hash_registry.states.lock().unwrap();
+        if states.is_empty() {
+            return;
+        }
+
+        let mut failed = Vec::new();
+        let (mut pending, mut in_progress, mut inconsistent) = (0, 0, 0);
+        for (path, state) in states.iter() {
+            match state {
+                FileState::Pending => pending += 1,
+                FileState::InProgress => in_progress += 1,
+                FileState::Failed { error, attempts } => failed.push((path, error, attempts)),
+                FileState::Inconsistent => inconsistent += 1,
+            }
+        }
+
+        println!(
+            "Left unfinished: {} files never started, {} interrupted while being written, {} changed while being read, {} failed",
+            pending,
+            in_progress,
+            inconsistent,
+            failed.len()
+        );
+        failed.sort();
+        for (path, error, attempts) in failed {
+            println!("  {} failed {} times: {}", path.display(), attempts, error);
+        }
+    }
+
+    /// Resume a previously interrupted backup, retrying files that failed and redoing files
+    /// that were interrupted while being written
     pub fn resume(&mut self) -> Result<()> {
-        let files_to_process = self.collect_files_to_process()?;
+        let mut report = RunReport::start();
+        if let Some(destination_path) = &self.config.destination_path {
+            if let Some(last) = report::latest_report(destination_path)? {
+                if last.status == RunStatus::Interrupted {
+                    println!(
+                        "Picking up run {}, which was interrupted with {} files remaining",
+                        last.id, last.files_remaining
+                    );
+                }
+            }
+        }
+
+        self.describe_unfinished_files();
+
+        self.apply_resource_limits()?;
+        let scan = self.scan_source()?;
+        if self.is_stopping() {
+            return self.stop_run(report);
+        }
+        self.remove_leftover_temp_files(&scan.files_to_process, &scan.sizes)?;
+        report.failures.extend(scan.failures);
+        self.handle_deleted_files(&scan.deleted_files, &mut report)?;
+        let files_to_process = scan.files_to_process;
+        self.hash_registry.plan(&files_to_process);
         
         if files_to_process.is_empty() {
             println!("No files to resume. The backup is already complete.");
-            return Ok(());
+        } else {
+            println!("Resuming backup with {} files remaining", files_to_process.len());
+            self.process_files(
+                files_to_process,
+                &scan.sizes,
+                "Resume completed".to_string(),
+                &mut report,
+            )?;
+            if self.is_stopping() {
+                return self.stop_run(report);
+            }
         }
-        
-        println!("Resuming backup with {} files remaining", files_to_process.len());
-        self.process_files(files_to_process, "Resume completed".to_string())
+
+        self.finish_run(report, &scan.links)
     }
 }
 
-pub fn process_file(
+/// Device and inode of a file with more than one hard link
+#[cfg(unix)]
+fn hardlink_id(metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+
+    (metadata.nlink() > 1).then(|| (metadata.dev(), metadata.ino()))
+}
+
+/// Hard links are only detected on Unix
+#[cfg(not(unix))]
+fn hardlink_id(_metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// Path of the link behind a walk error, if the error came from following a link that dangles
+/// or points back up the tree it's in
+fn unfollowable_link(error: &walkdir::Error) -> Option<&Path> {
+    let path = error.path()?;
+    let is_link = fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_symlink());
+    let unfollowable = error.loop_ancestor().is_some() || fs::metadata(path).is_err();
+    (is_link && unfollowable).then_some(path)
+}
+
+/// Read where the link at `path` points, adding it to `symlinks`, or to `failures` if it can't
+/// be read
+fn read_symlink(path: &Path, symlinks: &mut Vec<(PathBuf, PathBuf)>, failures: &mut Vec<FileFailure>) {
+    match fs::read_link(path) {
+        Ok(target) => symlinks.push((path.to_path_buf(), target)),
+        Err(e) => {
+            let error = e.into();
+            eprintln!("Error reading link {}: {:#}", path.display(), error);
+            failures.push(FileFailure::new(path.to_path_buf(), Operation::Read, &error));
+        }
+    }
+}
+
+/// Print how many failures there were of each operation, followed by the first few of them
+fn describe_failures(failures: &[FileFailure]) {
+    let mut counts: Vec<(Operation, usize)> = Vec::new();
+    for failure in failures {
+        match counts.iter_mut().find(|(operation, _)| *operation == failure.operation) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((failure.operation, 1)),
+        }
+    }
+    let counts: Vec<String> = counts
+        .iter()
+        .map(|(operation, count)| format!("{} {}", count, operation.name()))
+        .collect();
+    println!("{} failures: {}", failures.len(), counts.join(", "));
+
+    for failure in failures.iter().take(FAILURES_SHOWN) {
+        println!(
+            "  {} ({}, {}): {}",
+            failure.path.display(),
+            failure.operation.name(),
+            failure.kind,
+            failure.error
+        );
+    }
+    if failures.len() > FAILURES_SHOWN {
+        println!("  ... and {} more", failures.len() - FAILURES_SHOWN);
+    }
+}
+
+/// Path of the compressed copy of `source_file` inside `destination_root`
+pub fn destination_path_for(
     source_file: &Path,
     source_root: &Path,
     destination_root: &Path,
-) -> Result<String> {
+) -> Result<PathBuf> {
     // Calculate relative path from source root
     let relative_path = source_file.strip_prefix(source_root)?;
 
@@ -160,29 +1247,87 @@ pub fn process_file(
             .map_or("", |e| e.to_str().unwrap_or(""))
     ));
 
-    // Create parent directories if needed
-    if let Some(parent) = destination_file.parent() {
-        fs::create_dir_all(parent)?;
-    }
+    Ok(destination_file)
+}
 
-    // Compress the file
-    compression::compress_file(source_file, &destination_file)?;
+/// Move the stored copy of a file's previous version out of the way before it's overwritten,
+/// so older snapshots can still restore it
+fn retire_previous_version(
+    source_file: &Path,
+    source: &Source,
+    destination_root: &Path,
+    previous_hash: &str,
+) -> Result<()> {
+    let mirror_root = destination_root.join(&source.prefix);
+    let destination_file = destination_path_for(source_file, &source.path, &mirror_root)?;
+    snapshot::retire_object(destination_root, &destination_file, previous_hash)?;
 
-    // Calculate hash and return it
-    let hash = hash_file(source_file)?;
-    
-    Ok(hash)
+    // A file that was deleted and has reappeared may have its old copy in the attic
+    let attic_root = destination_root.join(ATTIC_DIR).join(&source.prefix);
+    let attic_file = destination_path_for(source_file, &source.path, &attic_root)?;
+    snapshot::retire_object(destination_root, &attic_file, previous_hash)
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::io::{Read, Write};
-    use std::fs::File;
-    use tempfile::{NamedTempFile, TempDir};
+/// Keep, move to the attic, or remove the backed up copy of a deleted source file. A removed
+/// copy, last stored with `hash`, is retired to the versions folder so the snapshots that
+/// still list the file can restore it.
+pub fn apply_deletion_policy(
+    policy: DeletionPolicy,
+    source_file: &Path,
+    source: &Source,
+    destination_root: &Path,
+    hash: &str,
+) -> Result<()> {
+    let mirror_root = destination_root.join(&source.prefix);
+    let destination_file = destination_path_for(source_file, &source.path, &mirror_root)?;
+    if policy == DeletionPolicy::Keep || !destination_file.exists() {
+        return Ok(());
+    }
 
-    #[test]
-    fn test_backup_job_new() {
+    match policy {
+        DeletionPolicy::Keep => {}
+        DeletionPolicy::Attic => {
+            let attic_root = destination_root.join(ATTIC_DIR).join(&source.prefix);
+            let attic_file = destination_path_for(source_file, &source.path, &attic_root)?;
+            if let Some(parent) = attic_file.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::rename(&destination_file, &attic_file)?;
+        }
+        DeletionPolicy::Remove => snapshot::retire_object(destination_root, &destination_file, hash)?,
+    }
+
+    Ok(())
+}
+
+pub fn process_file(
+    source_file: &Path,
+    source_root: &Path,
+    destination_root: &Path,
+    durability: Durability,
+) -> Result<String> {
+    let destination_file = destination_path_for(source_file, source_root, destination_root)?;
+
+    // Create parent directories if needed
+    if let Some(parent) = destination_file.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    // Compress the file, hashing the same bytes as they are read
+    let hash = compression::compress_file(source_file, &destination_file, durability)?;
+
+    Ok(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{self, Read, Write};
+    use std::fs::File;
+    use tempfile::{NamedTempFile, TempDir};
+
+    #[test]
+    fn test_backup_job_new() {
         // Create a config and hash registry
         let config = Config::default();
         let hash_registry = HashRegistry::new();
@@ -218,7 +1363,8 @@ mod tests {
         let hash = process_file(
             &test_file_path, 
             source_dir.path(),
-            dest_dir.path()
+            dest_dir.path(),
+            Durability::File
         ).unwrap();
         
         // Verify the hash is correct
@@ -260,7 +1406,8 @@ mod tests {
         process_file(
             &test_file_path, 
             source_dir.path(),
-            dest_dir.path()
+            dest_dir.path(),
+            Durability::File
         ).unwrap();
         
         // Verify a compressed file was created in the destination directory
@@ -285,10 +1432,12 @@ mod tests {
         let hash_file = NamedTempFile::new().unwrap();
         
         // Create config and hash registry
-        let mut config = Config::default();
-        config.source_path = Some(PathBuf::from(source_dir.path()));
-        config.destination_path = Some(PathBuf::from(dest_dir.path()));
-        config.hash_file_path = Some(PathBuf::from(hash_file.path()));
+        let config = Config {
+            source_path: Some(PathBuf::from(source_dir.path())),
+            destination_path: Some(PathBuf::from(dest_dir.path())),
+            hash_file_path: Some(PathBuf::from(hash_file.path())),
+            ..Config::default()
+        };
         
         let hash_registry = HashRegistry::new();
         let mut backup_job = BackupJob::new(config, hash_registry);
@@ -316,10 +1465,12 @@ mod tests {
         blacklisted_file.write_all(b"Blacklisted content").unwrap();
         
         // Create config and hash registry
-        let mut config = Config::default();
-        config.source_path = Some(source_dir.path().to_path_buf());
-        config.destination_path = Some(dest_dir.path().to_path_buf());
-        config.hash_file_path = Some(hash_file.path().to_path_buf());
+        let config = Config {
+            source_path: Some(source_dir.path().to_path_buf()),
+            destination_path: Some(dest_dir.path().to_path_buf()),
+            hash_file_path: Some(hash_file.path().to_path_buf()),
+            ..Config::default()
+        };
         
         let hash_registry = HashRegistry::new();
         let mut backup_job = BackupJob::new(config, hash_registry);
@@ -341,6 +1492,437 @@ mod tests {
         assert!(!backup_job.hash_registry.has_hash(&blacklisted_path));
     }
 
+    #[test]
+    fn test_backup_job_removes_leftover_temp_files() {
+        let source_dir = TempDir::new().unwrap();
+        let dest_dir = TempDir::new().unwrap();
+        fs::write(source_dir.path().join("test.txt"), b"Test content").unwrap();
+
+        // Simulate a run that was interrupted halfway through compressing a file
+        let leftover = compression::temp_path_for(&dest_dir.path().join("test.txt.zst"));
+        fs::write(&leftover, b"Partial data").unwrap();
+
+        let config = Config {
+            source_path: Some(source_dir.path().to_path_buf()),
+            destination_path: Some(dest_dir.path().to_path_buf()),
+            durability: Durability::Full,
+            ..Config::default()
+        };
+        let mut backup_job = BackupJob::new(config, HashRegistry::new());
+        backup_job.run().unwrap();
+
+        // The temporary file is gone and the real copy is complete
+        assert!(!leftover.exists());
+        let decompressed_path = dest_dir.path().join("decompressed.txt");
+        compression::decompress_file(dest_dir.path().join("test.txt.zst"), &decompressed_path).unwrap();
+        assert_eq!(fs::read(&decompressed_path).unwrap(), b"Test content");
+    }
+
+    #[test]
+    fn test_backup_job_resume_skips_journaled_files() {
+        let source_dir = TempDir::new().unwrap();
+        let dest_dir = TempDir::new().unwrap();
+        let registry_dir = TempDir::new().unwrap();
+        let registry_path = registry_dir.path().join("hashes.json");
+        let finished_path = source_dir.path().join("finished.txt");
+        let pending_path = source_dir.path().join("pending.txt");
+        fs::write(&finished_path, b"Backed up before the crash").unwrap();
+        fs::write(&pending_path, b"Not backed up yet").unwrap();
+
+        // A crashed run journaled one finished file but never saved the registry
+        Journal::open(&registry_path, Durability::File)
+            .unwrap()
+            .append(&JournalEntry::Stored {
+                path: finished_path.clone(),
+                hash: super::hash_file(&finished_path).unwrap(),
+                stamp: FileStamp::from_path(&finished_path).unwrap(),
+                metadata: None,
+            })
+            .unwrap();
+
+        let config = Config {
+            source_path: Some(source_dir.path().to_path_buf()),
+            destination_path: Some(dest_dir.path().to_path_buf()),
+            hash_file_path: Some(registry_path.clone()),
+            ..Config::default()
+        };
+        let hash_registry = HashRegistry::load_from_file(&registry_path).unwrap();
+        let mut backup_job = BackupJob::new(config, hash_registry);
+        backup_job.resume().unwrap();
+
+        // Only the pending file is processed again
+        assert!(!dest_dir.path().join("finished.txt.zst").exists());
+        assert!(dest_dir.path().join("pending.txt.zst").exists());
+
+        // The registry now holds both files and the journal has been compacted into it
+        let saved = HashRegistry::load_from_file(&registry_path).unwrap();
+        assert_eq!(saved.len(), 2);
+        let journal_file = crate::hashing::journal_path(&registry_path);
+        assert_eq!(fs::metadata(journal_file).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_backup_job_stops_before_starting_new_files() {
+        let source_dir = TempDir::new().unwrap();
+        let dest_dir = TempDir::new().unwrap();
+        let registry_dir = TempDir::new().unwrap();
+        let registry_path = registry_dir.path().join("hashes.json");
+        let files: Vec<PathBuf> = (0..3)
+            .map(|i| {
+                let path = source_dir.path().join(format!("file{}.txt", i));
+                fs::write(&path, format!("Content {}", i)).unwrap();
+                path
+            })
+            .collect();
+
+        let config = Config {
+            source_path: Some(source_dir.path().to_path_buf()),
+            destination_path: Some(dest_dir.path().to_path_buf()),
+            hash_file_path: Some(registry_path.clone()),
+            ..Config::default()
+        };
+        let mut backup_job = BackupJob::new(config, HashRegistry::new());
+
+        // Once asked to stop, no file is started and all of them are left for later
+        backup_job.stop.store(true, Ordering::SeqCst);
+        let mut report = RunReport::start();
+        backup_job
+            .process_files(files, &HashMap::new(), "Backup completed".to_string(), &mut report)
+            .unwrap();
+        assert_eq!(report.files_processed, 0);
+        assert_eq!(report.files_remaining, 3);
+        assert!(!dest_dir.path().join("file0.txt.zst").exists());
+
+        // Stopping saves the registry and an interrupted report, but no snapshot
+        let err = backup_job.stop_run(report).unwrap_err();
+        assert!(err.is::<Interrupted>());
+        assert!(registry_path.exists());
+        let last = report::latest_report(dest_dir.path()).unwrap().unwrap();
+        assert_eq!(last.status, RunStatus::Interrupted);
+        assert_eq!(last.files_remaining, 3);
+        assert!(snapshot::list_snapshots(dest_dir.path()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_backup_job_reports_failed_files() {
+        let source_dir = TempDir::new().unwrap();
+        let dest_dir = TempDir::new().unwrap();
+        let present = source_dir.path().join("present.txt");
+        fs::write(&present, b"Present").unwrap();
+        let vanished = source_dir.path().join("vanished.txt");
+
+        let config = Config {
+            source_path: Some(source_dir.path().to_path_buf()),
+            destination_path: Some(dest_dir.path().to_path_buf()),
+            ..Config::default()
+        };
+        let mut backup_job = BackupJob::new(config, HashRegistry::new());
+
+        // A file that disappears after the scan fails to be read, the others are backed up
+        let mut report = RunReport::start();
+        backup_job
+            .process_files(
+                vec![present.clone(), vanished.clone()],
+                &HashMap::new(),
+                "Backup completed".to_string(),
+                &mut report,
+            )
+            .unwrap();
+        assert_eq!(report.files_processed, 1);
+        assert_eq!(report.failures.len(), 1);
+        assert_eq!(report.failures[0].path, vanished);
+        assert_eq!(report.failures[0].operation, Operation::Read);
+        assert_eq!(report.failures[0].kind, "NotFound");
+
+        // The run still finishes, but reports the failure
+        let err = backup_job.finish_run(report, &ScannedLinks::default()).unwrap_err();
+        let failed = err.downcast_ref::<FilesFailed>().unwrap();
+        assert_eq!(failed.count, 1);
+        let saved: RunReport = serde_json::from_str(&fs::read_to_string(&failed.report).unwrap()).unwrap();
+        assert_eq!(saved.status, RunStatus::Completed);
+        assert_eq!(saved.failures[0].path, vanished);
+    }
+
+    #[test]
+    fn test_backup_job_rereads_files_that_change() {
+        let source_dir = TempDir::new().unwrap();
+        let log_file = source_dir.path().join("app.log");
+        fs::write(&log_file, b"First line\n").unwrap();
+        let config = Config {
+            changed_file_retries: 1,
+            ..Config::default()
+        };
+        let backup_job = BackupJob::new(config, HashRegistry::new());
+        let append = |line: &str| {
+            let mut file = fs::OpenOptions::new().append(true).open(&log_file).unwrap();
+            file.write_all(line.as_bytes()).unwrap();
+        };
+        let stored = || StoredObject {
+            hash: "hash".to_string(),
+            newly_stored: true,
+        };
+
+        // A file written to during the first read is read again and then consistent
+        let mut reads = 0;
+        let file = backup_job
+            .store_unchanged(&log_file, || {
+                reads += 1;
+                if reads == 1 {
+                    append("Second line\n");
+                }
+                Ok(stored())
+            })
+            .unwrap();
+        assert_eq!(reads, 2);
+        assert!(file.consistent);
+        assert_eq!(file.stamp, FileStamp::from_path(&log_file).unwrap());
+
+        // A file written to during every read is kept after the configured rereads
+        let mut reads = 0;
+        let file = backup_job
+            .store_unchanged(&log_file, || {
+                reads += 1;
+                append("Another line\n");
+                Ok(stored())
+            })
+            .unwrap();
+        assert_eq!(reads, 2);
+        assert!(!file.consistent);
+    }
+
+    #[test]
+    fn test_backup_job_retries_transient_errors() {
+        let mut config = Config::default();
+        config.retry.backoff_ms = 1;
+        let backup_job = BackupJob::new(config, HashRegistry::new());
+        let path = Path::new("flaky.txt");
+        let transient = || anyhow::Error::from(io::Error::from(io::ErrorKind::TimedOut));
+
+        // A transient error that clears up on the second try
+        let mut tries = 0;
+        let (result, retry) = backup_job.with_retries(path, || {
+            tries += 1;
+            if tries < 2 { Err(transient()) } else { Ok(tries) }
+        });
+        assert_eq!(result.unwrap(), 2);
+        let retry = retry.unwrap();
+        assert_eq!(retry.attempts, 2);
+        assert_eq!(retry.kind, "TimedOut");
+        assert!(retry.recovered);
+
+        // A transient error that persists is given up on after the configured attempts
+        let mut tries = 0;
+        let (result, retry) = backup_job.with_retries(path, || -> Result<()> {
+            tries += 1;
+            Err(transient())
+        });
+        assert!(result.is_err());
+        assert_eq!(tries, 3);
+        assert!(!retry.unwrap().recovered);
+
+        // Other errors fail right away
+        let mut tries = 0;
+        let (result, retry) = backup_job.with_retries(path, || -> Result<()> {
+            tries += 1;
+            Err(io::Error::from(io::ErrorKind::NotFound).into())
+        });
+        assert!(result.is_err());
+        assert_eq!(tries, 1);
+        assert!(retry.is_none());
+    }
+
+    #[test]
+    fn test_backup_job_reports_walk_errors() {
+        let dest_dir = TempDir::new().unwrap();
+        let missing_source = dest_dir.path().join("missing");
+
+        let config = Config {
+            source_path: Some(missing_source.clone()),
+            destination_path: Some(dest_dir.path().to_path_buf()),
+            ..Config::default()
+        };
+        let mut backup_job = BackupJob::new(config, HashRegistry::new());
+        let scan = backup_job.scan_source().unwrap();
+        assert_eq!(scan.failures.len(), 1);
+        assert_eq!(scan.failures[0].path, missing_source);
+        assert_eq!(scan.failures[0].operation, Operation::Walk);
+        assert_eq!(scan.failures[0].kind, "NotFound");
+
+        let err = backup_job.run().unwrap_err();
+        assert!(err.is::<FilesFailed>());
+    }
+
+    #[test]
+    fn test_backup_job_with_single_worker() {
+        let source_dir = TempDir::new().unwrap();
+        let dest_dir = TempDir::new().unwrap();
+        for i in 0..5 {
+            fs::write(source_dir.path().join(format!("file_{}.txt", i)), format!("Content {}", i)).unwrap();
+        }
+
+        let config = Config {
+            source_path: Some(source_dir.path().to_path_buf()),
+            destination_path: Some(dest_dir.path().to_path_buf()),
+            workers: Some(1),
+            ..Config::default()
+        };
+        let mut backup_job = BackupJob::new(config, HashRegistry::new());
+        assert_eq!(backup_job.worker_pool().unwrap().current_num_threads(), 1);
+        backup_job.run().unwrap();
+
+        assert_eq!(backup_job.hash_registry.len(), 5);
+        for i in 0..5 {
+            assert!(dest_dir.path().join(format!("file_{}.txt.zst", i)).exists());
+        }
+    }
+
+    #[test]
+    fn test_backup_job_schedules_largest_files_first() {
+        let source_dir = TempDir::new().unwrap();
+        let dest_dir = TempDir::new().unwrap();
+        fs::write(source_dir.path().join("a_small.txt"), b"Small").unwrap();
+        fs::write(source_dir.path().join("b_large.txt"), "Large ".repeat(1000)).unwrap();
+        fs::write(source_dir.path().join("c_medium.txt"), "Medium ".repeat(100)).unwrap();
+        fs::write(source_dir.path().join("d_small.txt"), b"Small").unwrap();
+
+        let config = Config {
+            source_path: Some(source_dir.path().to_path_buf()),
+            destination_path: Some(dest_dir.path().to_path_buf()),
+            ..Config::default()
+        };
+        let mut backup_job = BackupJob::new(config, HashRegistry::new());
+        let scan = backup_job.scan_source().unwrap();
+
+        // Largest first, with files of the same size in path order
+        let names: Vec<_> = scan
+            .files_to_process
+            .iter()
+            .map(|path| path.file_name().unwrap().to_str().unwrap())
+            .collect();
+        assert_eq!(names, ["b_large.txt", "c_medium.txt", "a_small.txt", "d_small.txt"]);
+        assert_eq!(scan.sizes[&source_dir.path().join("b_large.txt")], 6000);
+    }
+
+    #[test]
+    fn test_backup_job_interrupted_scan_keeps_tracked_files() {
+        let source_dir = TempDir::new().unwrap();
+        let dest_dir = TempDir::new().unwrap();
+        let test_file_path = source_dir.path().join("test.txt");
+        fs::write(&test_file_path, b"Test content").unwrap();
+
+        let config = Config {
+            source_path: Some(source_dir.path().to_path_buf()),
+            destination_path: Some(dest_dir.path().to_path_buf()),
+            ..Config::default()
+        };
+        let mut backup_job = BackupJob::new(config, HashRegistry::new());
+        backup_job.run().unwrap();
+
+        // A run stopped during the scan must not take unscanned files for deleted ones
+        backup_job.stop.store(true, Ordering::SeqCst);
+        let err = backup_job.run().unwrap_err();
+        assert!(err.is::<Interrupted>());
+        assert!(backup_job.hash_registry.has_hash(&test_file_path));
+        assert!(backup_job.hash_registry.tombstones.lock().unwrap().is_empty());
+
+        // Resuming afterwards completes normally
+        backup_job.stop.store(false, Ordering::SeqCst);
+        backup_job.resume().unwrap();
+        let last = report::latest_report(dest_dir.path()).unwrap().unwrap();
+        assert_eq!(last.status, RunStatus::Completed);
+    }
+
+    #[test]
+    fn test_backup_job_resume_redoes_interrupted_files() {
+        let source_dir = TempDir::new().unwrap();
+        let dest_dir = TempDir::new().unwrap();
+        let registry_dir = TempDir::new().unwrap();
+        let registry_path = registry_dir.path().join("hashes.json");
+        let test_file_path = source_dir.path().join("test.txt");
+        fs::write(&test_file_path, b"Test content").unwrap();
+
+        let config = Config {
+            source_path: Some(source_dir.path().to_path_buf()),
+            destination_path: Some(dest_dir.path().to_path_buf()),
+            hash_file_path: Some(registry_path.clone()),
+            ..Config::default()
+        };
+        let mut backup_job = BackupJob::new(config, HashRegistry::new());
+        backup_job.run().unwrap();
+
+        // A later run crashed after starting on the file and losing its stored copy
+        let stored_copy = dest_dir.path().join("test.txt.zst");
+        fs::remove_file(&stored_copy).unwrap();
+        Journal::open(&registry_path, Durability::File)
+            .unwrap()
+            .append(&JournalEntry::Started { path: test_file_path.clone() })
+            .unwrap();
+
+        // The file is unchanged, but resume still redoes it because it was left in progress
+        let hash_registry = HashRegistry::load_from_file(&registry_path).unwrap();
+        assert_eq!(hash_registry.get_state(&test_file_path), Some(FileState::InProgress));
+        backup_job.hash_registry = hash_registry;
+        backup_job.resume().unwrap();
+
+        assert!(stored_copy.exists());
+        assert_eq!(backup_job.hash_registry.get_state(&test_file_path), None);
+        let saved = HashRegistry::load_from_file(&registry_path).unwrap();
+        assert!(saved.states.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_backup_job_dry_run() {
+        let source_dir = TempDir::new().unwrap();
+        let dest_dir = TempDir::new().unwrap();
+        let registry_dir = TempDir::new().unwrap();
+        let registry_path = registry_dir.path().join("hashes.json");
+        let unchanged = source_dir.path().join("unchanged.txt");
+        let changed = source_dir.path().join("changed.txt");
+        let retried = source_dir.path().join("retried.txt");
+        let deleted = source_dir.path().join("deleted.txt");
+        fs::write(&unchanged, b"Unchanged content").unwrap();
+        fs::write(&changed, b"Original content").unwrap();
+        fs::write(&retried, b"Retried content").unwrap();
+        fs::write(&deleted, b"Deleted content").unwrap();
+
+        let config = Config {
+            source_path: Some(source_dir.path().to_path_buf()),
+            destination_path: Some(dest_dir.path().to_path_buf()),
+            hash_file_path: Some(registry_path.clone()),
+            ..Config::default()
+        };
+        let mut backup_job = BackupJob::new(config, HashRegistry::new());
+        backup_job.run().unwrap();
+
+        fs::write(&changed, b"Changed content, now longer").unwrap();
+        fs::remove_file(&deleted).unwrap();
+        // Files left unfinished by an earlier run are retried only if their content is the same
+        backup_job.hash_registry.record_failure(retried.clone(), "Disk full".to_string());
+        backup_job.hash_registry.mark_inconsistent(changed.clone());
+        let new = source_dir.path().join("new.txt");
+        fs::write(&new, "New content ".repeat(100)).unwrap();
+        let excluded = source_dir.path().join("program.exe");
+        fs::write(&excluded, b"Excluded content").unwrap();
+
+        let registry_before = fs::read(&registry_path).unwrap();
+        let destination_before = WalkDir::new(dest_dir.path()).into_iter().count();
+
+        // Every file is put in the right category
+        let report = backup_job.dry_run().unwrap();
+        assert_eq!(report.new_files, vec![new]);
+        assert_eq!(report.changed_files, vec![changed]);
+        assert_eq!(report.retried_files, vec![retried]);
+        assert_eq!(report.unchanged_files, vec![unchanged]);
+        assert_eq!(report.excluded_files, vec![excluded]);
+        assert_eq!(report.deleted_files, vec![deleted]);
+        assert_eq!(report.total_bytes, 1200 + 27 + 15);
+        assert!(report.estimated_compressed_bytes < report.total_bytes);
+
+        // Nothing was written
+        assert_eq!(fs::read(&registry_path).unwrap(), registry_before);
+        assert_eq!(WalkDir::new(dest_dir.path()).into_iter().count(), destination_before);
+    }
+
     #[test]
     fn test_backup_job_run_with_blacklist() {
         // Create source and destination directories
@@ -363,10 +1945,12 @@ mod tests {
         file.write_all(b"Blacklisted directory file").unwrap();
         
         // Create config with default blacklist (which includes node_modules)
-        let mut config = Config::default();
-        config.source_path = Some(source_dir.path().to_path_buf());
-        config.destination_path = Some(dest_dir.path().to_path_buf());
-        config.hash_file_path = Some(hash_file.path().to_path_buf());
+        let config = Config {
+            source_path: Some(source_dir.path().to_path_buf()),
+            destination_path: Some(dest_dir.path().to_path_buf()),
+            hash_file_path: Some(hash_file.path().to_path_buf()),
+            ..Config::default()
+        };
         
         let hash_registry = HashRegistry::new();
         let mut backup_job = BackupJob::new(config, hash_registry);
@@ -383,6 +1967,249 @@ mod tests {
         assert!(!expected_blacklisted_path.exists());
     }
 
+    #[test]
+    fn test_backup_job_multiple_sources() {
+        let home_dir = TempDir::new().unwrap();
+        let photos_dir = TempDir::new().unwrap();
+        let dest_dir = TempDir::new().unwrap();
+        let hash_file = NamedTempFile::new().unwrap();
+        fs::write(home_dir.path().join("notes.txt"), b"Notes").unwrap();
+        let photo_path = photos_dir.path().join("2024/beach.jpg");
+        fs::create_dir_all(photo_path.parent().unwrap()).unwrap();
+        fs::write(&photo_path, b"Photo").unwrap();
+        fs::write(photos_dir.path().join("2024/beach.xmp"), b"Sidecar").unwrap();
+
+        let config = Config {
+            destination_path: Some(dest_dir.path().to_path_buf()),
+            hash_file_path: Some(hash_file.path().to_path_buf()),
+            deletion_policy: DeletionPolicy::Attic,
+            sources: vec![
+                Source {
+                    path: home_dir.path().to_path_buf(),
+                    prefix: PathBuf::from("home"),
+                    blacklist_dirs: HashSet::new(),
+                    blacklist_extensions: HashSet::new(),
+                },
+                Source {
+                    path: photos_dir.path().to_path_buf(),
+                    prefix: PathBuf::from("media/photos"),
+                    blacklist_dirs: HashSet::new(),
+                    blacklist_extensions: HashSet::from(["xmp".to_string()]),
+                },
+            ],
+            ..Config::default()
+        };
+        let mut backup_job = BackupJob::new(config, HashRegistry::new());
+        backup_job.run().unwrap();
+
+        // Each source lands under its own prefix and keeps its own excludes
+        assert!(dest_dir.path().join("home/notes.txt.zst").exists());
+        assert!(dest_dir.path().join("media/photos/2024/beach.jpg.zst").exists());
+        assert!(!dest_dir.path().join("media/photos/2024/beach.xmp.zst").exists());
+        assert_eq!(backup_job.hash_registry.len(), 2);
+
+        let snapshot = snapshot::find_snapshot(dest_dir.path(), None).unwrap();
+        assert_eq!(snapshot.sources.len(), 2);
+        let mut paths: Vec<_> = snapshot.files.iter().map(|entry| entry.path.clone()).collect();
+        paths.sort();
+        assert_eq!(paths, vec![PathBuf::from("home/notes.txt"), PathBuf::from("media/photos/2024/beach.jpg")]);
+
+        // A file deleted from one source is moved to the attic under that source's prefix
+        fs::remove_file(&photo_path).unwrap();
+        backup_job.run().unwrap();
+        assert!(!dest_dir.path().join("media/photos/2024/beach.jpg.zst").exists());
+        assert!(dest_dir.path().join(ATTIC_DIR).join("media/photos/2024/beach.jpg.zst").exists());
+        assert!(dest_dir.path().join("home/notes.txt.zst").exists());
+    }
+
+    /// Back up a source holding a file, a link to it, a dangling link and a link back to the
+    /// source root, with `policy`
+    #[cfg(unix)]
+    fn run_with_symlinks(policy: SymlinkPolicy) -> (TempDir, TempDir, Snapshot) {
+        use std::os::unix::fs::symlink;
+
+        let source_dir = TempDir::new().unwrap();
+        let dest_dir = TempDir::new().unwrap();
+        fs::write(source_dir.path().join("notes.txt"), b"Notes").unwrap();
+        symlink("notes.txt", source_dir.path().join("latest.txt")).unwrap();
+        symlink("missing.txt", source_dir.path().join("dangling.txt")).unwrap();
+        symlink(source_dir.path(), source_dir.path().join("loop")).unwrap();
+
+        let config = Config {
+            source_path: Some(source_dir.path().to_path_buf()),
+            destination_path: Some(dest_dir.path().to_path_buf()),
+            symlinks: policy,
+            ..Config::default()
+        };
+        let mut backup_job = BackupJob::new(config, HashRegistry::new());
+        backup_job.run().unwrap();
+
+        let snapshot = snapshot::find_snapshot(dest_dir.path(), None).unwrap();
+        (source_dir, dest_dir, snapshot)
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_backup_job_preserves_symlinks() {
+        let (source_dir, dest_dir, snapshot) = run_with_symlinks(SymlinkPolicy::Preserve);
+
+        // Only the file's content is stored, the links are recorded with their targets
+        assert!(dest_dir.path().join("notes.txt.zst").exists());
+        assert!(!dest_dir.path().join("latest.txt.zst").exists());
+        assert_eq!(snapshot.files.len(), 1);
+        let links: Vec<(PathBuf, PathBuf)> = snapshot
+            .symlinks
+            .iter()
+            .map(|link| (link.path.clone(), link.target.clone()))
+            .collect();
+        assert_eq!(
+            links,
+            vec![
+                (PathBuf::from("dangling.txt"), PathBuf::from("missing.txt")),
+                (PathBuf::from("latest.txt"), PathBuf::from("notes.txt")),
+                (PathBuf::from("loop"), source_dir.path().to_path_buf()),
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_backup_job_follows_symlinks() {
+        let (_source_dir, dest_dir, snapshot) = run_with_symlinks(SymlinkPolicy::Follow);
+
+        // The link to the file is backed up as a copy of it, the links that can't be
+        // followed are kept as links
+        assert!(dest_dir.path().join("latest.txt.zst").exists());
+        assert_eq!(snapshot.files.len(), 2);
+        let links: Vec<&Path> = snapshot.symlinks.iter().map(|link| link.path.as_path()).collect();
+        assert_eq!(links, vec![Path::new("dangling.txt"), Path::new("loop")]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_backup_job_skips_symlinks() {
+        let (_source_dir, _dest_dir, snapshot) = run_with_symlinks(SymlinkPolicy::Skip);
+
+        assert_eq!(snapshot.files.len(), 1);
+        assert!(snapshot.symlinks.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_backup_job_stores_hardlinks_once() {
+        let source_dir = TempDir::new().unwrap();
+        let dest_dir = TempDir::new().unwrap();
+        let hash_file = NamedTempFile::new().unwrap();
+        let first = source_dir.path().join("a/cache.bin");
+        fs::create_dir_all(first.parent().unwrap()).unwrap();
+        fs::create_dir_all(source_dir.path().join("b")).unwrap();
+        fs::write(&first, b"Shared content").unwrap();
+        fs::hard_link(&first, source_dir.path().join("b/cache.bin")).unwrap();
+        fs::hard_link(&first, source_dir.path().join("b/copy.bin")).unwrap();
+
+        let config = Config {
+            source_path: Some(source_dir.path().to_path_buf()),
+            destination_path: Some(dest_dir.path().to_path_buf()),
+            hash_file_path: Some(hash_file.path().to_path_buf()),
+            ..Config::default()
+        };
+        let mut backup_job = BackupJob::new(config, HashRegistry::new());
+        backup_job.run().unwrap();
+
+        // The content is stored once, under the path that comes first
+        assert!(dest_dir.path().join("a/cache.bin.zst").exists());
+        assert!(!dest_dir.path().join("b/cache.bin.zst").exists());
+        assert_eq!(backup_job.hash_registry.len(), 1);
+
+        let snapshot = snapshot::find_snapshot(dest_dir.path(), None).unwrap();
+        assert_eq!(snapshot.files.len(), 1);
+        assert_eq!(
+            snapshot.files[0].links,
+            vec![PathBuf::from("b/cache.bin"), PathBuf::from("b/copy.bin")]
+        );
+
+        // The group is found again on the next run without storing anything new
+        backup_job.run().unwrap();
+        let snapshot = snapshot::find_snapshot(dest_dir.path(), None).unwrap();
+        assert_eq!(snapshot.files[0].path, PathBuf::from("a/cache.bin"));
+        assert_eq!(snapshot.files[0].links.len(), 2);
+        assert_eq!(backup_job.hash_registry.len(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_backup_job_reports_hardlinks_of_failed_files() {
+        let source_dir = TempDir::new().unwrap();
+        let dest_dir = TempDir::new().unwrap();
+        let first = source_dir.path().join("a.bin");
+        let link = source_dir.path().join("b.bin");
+
+        let config = Config {
+            source_path: Some(source_dir.path().to_path_buf()),
+            destination_path: Some(dest_dir.path().to_path_buf()),
+            ..Config::default()
+        };
+        let backup_job = BackupJob::new(config, HashRegistry::new());
+
+        // The first path of the group couldn't be read, so its link has nothing to point at
+        let mut report = RunReport::start();
+        let error = anyhow::anyhow!("Permission denied");
+        report.failures.push(FileFailure::new(first.clone(), Operation::Read, &error));
+        let links = ScannedLinks {
+            symlinks: Vec::new(),
+            hardlinks: vec![(link.clone(), first.clone())],
+        };
+        let err = backup_job.finish_run(report, &links).unwrap_err();
+        let failed = err.downcast_ref::<FilesFailed>().unwrap();
+        assert_eq!(failed.count, 2);
+        let saved: RunReport = serde_json::from_str(&fs::read_to_string(&failed.report).unwrap()).unwrap();
+        assert_eq!(saved.failures[1].path, link);
+        assert_eq!(saved.failures[1].operation, Operation::Read);
+        assert!(snapshot::find_snapshot(dest_dir.path(), None).unwrap().files.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_backup_job_restores_metadata() {
+        use std::fs::{FileTimes, OpenOptions};
+        use std::os::unix::fs::PermissionsExt;
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let source_dir = TempDir::new().unwrap();
+        let dest_dir = TempDir::new().unwrap();
+        let target_dir = TempDir::new().unwrap();
+        let hash_file = NamedTempFile::new().unwrap();
+        let script = source_dir.path().join("build.sh");
+        fs::write(&script, b"#!/bin/sh").unwrap();
+        let modified = Duration::new(1_700_000_000, 123_456_789);
+        let times = FileTimes::new().set_modified(UNIX_EPOCH + modified);
+        OpenOptions::new().write(true).open(&script).unwrap().set_times(times).unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let config = Config {
+            source_path: Some(source_dir.path().to_path_buf()),
+            destination_path: Some(dest_dir.path().to_path_buf()),
+            hash_file_path: Some(hash_file.path().to_path_buf()),
+            ..Config::default()
+        };
+        let mut backup_job = BackupJob::new(config, HashRegistry::new());
+        backup_job.run().unwrap();
+        let metadata = backup_job.hash_registry.get_metadata(&script).unwrap();
+        assert_eq!(metadata.modified, modified);
+        assert_eq!(metadata.mode, Some(0o755));
+
+        // A change of permissions alone leaves the content unchanged but is still picked up
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o700)).unwrap();
+        backup_job.run().unwrap();
+
+        let snapshot = snapshot::find_snapshot(dest_dir.path(), None).unwrap();
+        assert_eq!(snapshot.files[0].metadata.as_ref().unwrap().mode, Some(0o700));
+        crate::restore::restore_snapshot(&snapshot, dest_dir.path(), target_dir.path(), false).unwrap();
+        let restored = fs::metadata(target_dir.path().join("build.sh")).unwrap();
+        assert_eq!(restored.permissions().mode() & 0o7777, 0o700);
+        assert_eq!(restored.modified().unwrap(), UNIX_EPOCH + modified);
+    }
+
     #[test]
     fn test_backup_job_skips_processed_files() {
         // Create source and destination directories
@@ -396,14 +2223,17 @@ mod tests {
         test_file.write_all(b"Test content").unwrap();
         
         // Create config
-        let mut config = Config::default();
-        config.source_path = Some(source_dir.path().to_path_buf());
-        config.destination_path = Some(dest_dir.path().to_path_buf());
-        config.hash_file_path = Some(hash_file.path().to_path_buf());
+        let config = Config {
+            source_path: Some(source_dir.path().to_path_buf()),
+            destination_path: Some(dest_dir.path().to_path_buf()),
+            hash_file_path: Some(hash_file.path().to_path_buf()),
+            ..Config::default()
+        };
         
         // Create hash registry with the test file already marked as processed
-        let mut hash_registry = HashRegistry::new();
-        hash_registry.set_hash(test_file_path.clone(), "dummy_hash".to_string());
+        let hash_registry = HashRegistry::new();
+        let hash = super::hash_file(&test_file_path).unwrap();
+        hash_registry.set_hash(test_file_path.clone(), hash);
         
         // Create and run backup job
         let mut backup_job = BackupJob::new(config, hash_registry);
@@ -414,4 +2244,408 @@ mod tests {
         let expected_path = dest_dir.path().join("test.txt.zst");
         assert!(!expected_path.exists());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_backup_job_reprocesses_changed_files() {
+        // Create source and destination directories
+        let source_dir = TempDir::new().unwrap();
+        let dest_dir = TempDir::new().unwrap();
+        let hash_file = NamedTempFile::new().unwrap();
+
+        // Create a test file
+        let test_file_path = source_dir.path().join("test.txt");
+        fs::write(&test_file_path, b"Original content").unwrap();
+
+        // Create config
+        let config = Config {
+            source_path: Some(source_dir.path().to_path_buf()),
+            destination_path: Some(dest_dir.path().to_path_buf()),
+            hash_file_path: Some(hash_file.path().to_path_buf()),
+            ..Config::default()
+        };
+
+        // Run an initial backup
+        let mut backup_job = BackupJob::new(config, HashRegistry::new());
+        backup_job.run().unwrap();
+        let original_hash = backup_job.hash_registry.get_hash(&test_file_path).unwrap();
+
+        // Modify the file and run the backup again
+        fs::write(&test_file_path, b"Modified content, now longer").unwrap();
+        backup_job.run().unwrap();
+
+        // Verify the registry entry was updated to the new hash
+        let updated_hash = backup_job.hash_registry.get_hash(&test_file_path).unwrap();
+        assert_ne!(updated_hash, original_hash);
+        assert_eq!(updated_hash, super::hash_file(&test_file_path).unwrap());
+
+        // Verify the destination holds the modified content
+        let expected_path = dest_dir.path().join("test.txt.zst");
+        let decompressed_path = dest_dir.path().join("decompressed.txt");
+        compression::decompress_file(&expected_path, &decompressed_path).unwrap();
+        assert_eq!(fs::read(&decompressed_path).unwrap(), b"Modified content, now longer");
+    }
+
+    #[test]
+    fn test_backup_job_trusts_matching_stamp() {
+        // Create source and destination directories
+        let source_dir = TempDir::new().unwrap();
+        let dest_dir = TempDir::new().unwrap();
+        let hash_file = NamedTempFile::new().unwrap();
+
+        // Create a test file
+        let test_file_path = source_dir.path().join("test.txt");
+        fs::write(&test_file_path, b"Test content").unwrap();
+
+        // Create config
+        let config = Config {
+            source_path: Some(source_dir.path().to_path_buf()),
+            destination_path: Some(dest_dir.path().to_path_buf()),
+            hash_file_path: Some(hash_file.path().to_path_buf()),
+            ..Config::default()
+        };
+
+        // Record a stale hash alongside the file's current size and mtime
+        let hash_registry = HashRegistry::new();
+        hash_registry.set_hash(test_file_path.clone(), "stale_hash".to_string());
+        hash_registry.set_stamp(
+            test_file_path.clone(),
+            FileStamp::from_path(&test_file_path).unwrap(),
+        );
+
+        // The matching stamp means the file isn't hashed, so the stale hash goes unnoticed
+        let mut backup_job = BackupJob::new(config, hash_registry);
+        backup_job.run().unwrap();
+        let expected_path = dest_dir.path().join("test.txt.zst");
+        assert!(!expected_path.exists());
+
+        // Paranoid mode re-hashes the file and catches the mismatch
+        backup_job.paranoid = true;
+        backup_job.run().unwrap();
+        assert!(expected_path.exists());
+        assert_eq!(
+            backup_job.hash_registry.get_hash(&test_file_path),
+            Some(super::hash_file(&test_file_path).unwrap())
+        );
+    }
+
+    #[test]
+    fn test_backup_job_refreshes_stamp_for_unchanged_content() {
+        // Create source and destination directories
+        let source_dir = TempDir::new().unwrap();
+        let dest_dir = TempDir::new().unwrap();
+        let hash_file = NamedTempFile::new().unwrap();
+
+        // Create a test file
+        let test_file_path = source_dir.path().join("test.txt");
+        fs::write(&test_file_path, b"Test content").unwrap();
+
+        // Create config
+        let config = Config {
+            source_path: Some(source_dir.path().to_path_buf()),
+            destination_path: Some(dest_dir.path().to_path_buf()),
+            hash_file_path: Some(hash_file.path().to_path_buf()),
+            ..Config::default()
+        };
+
+        // Registry entry from before stamps were tracked: correct hash, no stamp
+        let hash_registry = HashRegistry::new();
+        let hash = super::hash_file(&test_file_path).unwrap();
+        hash_registry.set_hash(test_file_path.clone(), hash);
+
+        // The file is hashed, found unchanged, and its stamp recorded for next time
+        let mut backup_job = BackupJob::new(config, hash_registry);
+        backup_job.run().unwrap();
+        assert!(!dest_dir.path().join("test.txt.zst").exists());
+        assert_eq!(
+            backup_job.hash_registry.get_stamp(&test_file_path),
+            Some(FileStamp::from_path(&test_file_path).unwrap())
+        );
+    }
+
+    /// Back up a single file, delete it from the source and run again with `policy`
+    fn run_with_deleted_file(policy: DeletionPolicy) -> (BackupJob, TempDir, PathBuf) {
+        let source_dir = TempDir::new().unwrap();
+        let dest_dir = TempDir::new().unwrap();
+        let hash_file = NamedTempFile::new().unwrap();
+
+        // Create a test file and back it up
+        let test_file_path = source_dir.path().join("docs/test.txt");
+        fs::create_dir_all(test_file_path.parent().unwrap()).unwrap();
+        fs::write(&test_file_path, b"Test content").unwrap();
+
+        let config = Config {
+            source_path: Some(source_dir.path().to_path_buf()),
+            destination_path: Some(dest_dir.path().to_path_buf()),
+            hash_file_path: Some(hash_file.path().to_path_buf()),
+            deletion_policy: policy,
+            ..Config::default()
+        };
+        let mut backup_job = BackupJob::new(config, HashRegistry::new());
+        backup_job.run().unwrap();
+        assert!(dest_dir.path().join("docs/test.txt.zst").exists());
+
+        // Delete the source file and run again
+        fs::remove_file(&test_file_path).unwrap();
+        backup_job.run().unwrap();
+
+        (backup_job, dest_dir, test_file_path)
+    }
+
+    #[test]
+    fn test_backup_job_tombstones_deleted_files() {
+        let (backup_job, dest_dir, test_file_path) = run_with_deleted_file(DeletionPolicy::Keep);
+
+        // The registry entry becomes a tombstone
+        assert!(!backup_job.hash_registry.has_hash(&test_file_path));
+        let tombstones = backup_job.hash_registry.tombstones.lock().unwrap();
+        assert!(tombstones[&test_file_path].deleted_at > 0);
+
+        // The backed up copy is kept in place
+        assert!(dest_dir.path().join("docs/test.txt.zst").exists());
+    }
+
+    #[test]
+    fn test_backup_job_moves_deleted_files_to_attic() {
+        let (_, dest_dir, _) = run_with_deleted_file(DeletionPolicy::Attic);
+
+        assert!(!dest_dir.path().join("docs/test.txt.zst").exists());
+        assert!(dest_dir.path().join(ATTIC_DIR).join("docs/test.txt.zst").exists());
+    }
+
+    #[test]
+    fn test_backup_job_removes_deleted_files() {
+        let (_, dest_dir, _) = run_with_deleted_file(DeletionPolicy::Remove);
+
+        assert!(!dest_dir.path().join("docs/test.txt.zst").exists());
+        assert!(!dest_dir.path().join(ATTIC_DIR).exists());
+
+        // The snapshot taken before the deletion can still restore the file
+        let snapshots = snapshot::list_snapshots(dest_dir.path()).unwrap();
+        let target_dir = TempDir::new().unwrap();
+        crate::restore::restore_snapshot(&snapshots[0], dest_dir.path(), target_dir.path(), false).unwrap();
+        assert_eq!(fs::read(target_dir.path().join("docs/test.txt")).unwrap(), b"Test content");
+    }
+
+    #[test]
+    fn test_backup_job_keeps_shared_objects_of_deleted_files() {
+        let source_dir = TempDir::new().unwrap();
+        let dest_dir = TempDir::new().unwrap();
+        let test_file_path = source_dir.path().join("test.txt");
+        fs::write(&test_file_path, b"Test content").unwrap();
+
+        let config = Config {
+            source_path: Some(source_dir.path().to_path_buf()),
+            destination_path: Some(dest_dir.path().to_path_buf()),
+            storage_layout: StorageLayout::Objects,
+            deletion_policy: DeletionPolicy::Remove,
+            ..Config::default()
+        };
+        let mut backup_job = BackupJob::new(config, HashRegistry::new());
+        backup_job.run().unwrap();
+        let hash = backup_job.hash_registry.get_hash(&test_file_path).unwrap();
+
+        // The policy only applies to the mirror layout, so the object stays
+        fs::remove_file(&test_file_path).unwrap();
+        backup_job.run().unwrap();
+        assert!(dest_dir.path().join(store::object_path(&hash)).exists());
+        assert!(!dest_dir.path().join(ATTIC_DIR).exists());
+    }
+
+    #[test]
+    fn test_backup_job_keeps_versions_in_snapshots() {
+        // Create source and destination directories
+        let source_dir = TempDir::new().unwrap();
+        let dest_dir = TempDir::new().unwrap();
+        let hash_file = NamedTempFile::new().unwrap();
+
+        // Create a test file
+        let test_file_path = source_dir.path().join("test.txt");
+        fs::write(&test_file_path, b"First version").unwrap();
+
+        let config = Config {
+            source_path: Some(source_dir.path().to_path_buf()),
+            destination_path: Some(dest_dir.path().to_path_buf()),
+            hash_file_path: Some(hash_file.path().to_path_buf()),
+            ..Config::default()
+        };
+
+        // Back up two versions of the file
+        let mut backup_job = BackupJob::new(config, HashRegistry::new());
+        backup_job.run().unwrap();
+        fs::write(&test_file_path, b"Second, longer version").unwrap();
+        backup_job.run().unwrap();
+
+        // Each run created a snapshot describing the file at that time
+        let snapshots = snapshot::list_snapshots(dest_dir.path()).unwrap();
+        assert_eq!(snapshots.len(), 2);
+        assert_eq!(snapshots[0].files.len(), 1);
+        assert_eq!(snapshots[0].files[0].path, PathBuf::from("test.txt"));
+        assert_eq!(snapshots[0].files[0].size, 13);
+        assert_eq!(snapshots[1].files[0].size, 22);
+
+        // Both versions can be restored
+        let expected_contents = [&b"First version"[..], b"Second, longer version"];
+        for (snapshot, expected) in snapshots.iter().zip(expected_contents) {
+            let target_dir = TempDir::new().unwrap();
+            crate::restore::restore_snapshot(snapshot, dest_dir.path(), target_dir.path(), true).unwrap();
+            assert_eq!(fs::read(target_dir.path().join("test.txt")).unwrap(), expected);
+        }
+
+        // The mirror still holds the latest version
+        let decompressed_path = dest_dir.path().join("decompressed.txt");
+        compression::decompress_file(dest_dir.path().join("test.txt.zst"), &decompressed_path).unwrap();
+        assert_eq!(fs::read(&decompressed_path).unwrap(), b"Second, longer version");
+    }
+
+    #[test]
+    fn test_backup_job_objects_layout_deduplicates() {
+        // Create source and destination directories
+        let source_dir = TempDir::new().unwrap();
+        let dest_dir = TempDir::new().unwrap();
+        let hash_file = NamedTempFile::new().unwrap();
+
+        // Create two copies of the same file and one different file
+        fs::create_dir_all(source_dir.path().join("copy")).unwrap();
+        fs::write(source_dir.path().join("photo.jpg"), b"Same photo").unwrap();
+        fs::write(source_dir.path().join("copy/photo.jpg"), b"Same photo").unwrap();
+        fs::write(source_dir.path().join("notes.txt"), b"Different content").unwrap();
+
+        let config = Config {
+            source_path: Some(source_dir.path().to_path_buf()),
+            destination_path: Some(dest_dir.path().to_path_buf()),
+            hash_file_path: Some(hash_file.path().to_path_buf()),
+            storage_layout: StorageLayout::Objects,
+            ..Config::default()
+        };
+        let mut backup_job = BackupJob::new(config, HashRegistry::new());
+        backup_job.run().unwrap();
+
+        // Nothing is mirrored and the identical files share one object
+        assert!(!dest_dir.path().join("photo.jpg.zst").exists());
+        let object_count = WalkDir::new(dest_dir.path().join(store::OBJECTS_DIR))
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file())
+            .count();
+        assert_eq!(object_count, 2);
+
+        // The manifest maps both paths to the same object and restores them
+        let snapshot = snapshot::find_snapshot(dest_dir.path(), None).unwrap();
+        assert_eq!(snapshot.files.len(), 3);
+        assert_eq!(snapshot.files[0].path, PathBuf::from("copy/photo.jpg"));
+        assert_eq!(snapshot.files[2].path, PathBuf::from("photo.jpg"));
+        assert_eq!(snapshot.files[0].object, snapshot.files[2].object);
+
+        let target_dir = TempDir::new().unwrap();
+        crate::restore::restore_snapshot(&snapshot, dest_dir.path(), target_dir.path(), true).unwrap();
+        assert_eq!(fs::read(target_dir.path().join("copy/photo.jpg")).unwrap(), b"Same photo");
+        assert_eq!(fs::read(target_dir.path().join("notes.txt")).unwrap(), b"Different content");
+    }
+
+    #[test]
+    fn test_backup_job_snapshot_keeps_objects_across_layout_change() {
+        // Create source and destination directories
+        let source_dir = TempDir::new().unwrap();
+        let dest_dir = TempDir::new().unwrap();
+        let hash_file = NamedTempFile::new().unwrap();
+        fs::write(source_dir.path().join("old.txt"), b"Stored in the mirror").unwrap();
+
+        let config = Config {
+            source_path: Some(source_dir.path().to_path_buf()),
+            destination_path: Some(dest_dir.path().to_path_buf()),
+            hash_file_path: Some(hash_file.path().to_path_buf()),
+            ..Config::default()
+        };
+        let mut backup_job = BackupJob::new(config, HashRegistry::new());
+        backup_job.run().unwrap();
+
+        // Switch to the object layout and add a file
+        backup_job.config.storage_layout = StorageLayout::Objects;
+        fs::write(source_dir.path().join("new.txt"), b"Stored as an object").unwrap();
+        backup_job.run().unwrap();
+
+        // The unchanged file still points at its mirrored copy
+        let snapshot = snapshot::find_snapshot(dest_dir.path(), None).unwrap();
+        assert_eq!(snapshot.files[0].path, PathBuf::from("new.txt"));
+        assert!(snapshot.files[0].object.starts_with(store::OBJECTS_DIR));
+        assert_eq!(snapshot.files[1].object, PathBuf::from("old.txt.zst"));
+    }
+
+    #[test]
+    fn test_backup_job_chunked_layout() {
+        // Create source and destination directories
+        let source_dir = TempDir::new().unwrap();
+        let dest_dir = TempDir::new().unwrap();
+        let hash_file = NamedTempFile::new().unwrap();
+
+        // One file large enough to be chunked and one small file
+        let large_content: Vec<u8> = (0..store::CHUNKING_THRESHOLD as usize + 1024)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect();
+        fs::write(source_dir.path().join("disk.vhdx"), &large_content).unwrap();
+        fs::write(source_dir.path().join("small.txt"), b"Small file").unwrap();
+
+        let config = Config {
+            source_path: Some(source_dir.path().to_path_buf()),
+            destination_path: Some(dest_dir.path().to_path_buf()),
+            hash_file_path: Some(hash_file.path().to_path_buf()),
+            storage_layout: StorageLayout::Chunked,
+            ..Config::default()
+        };
+        let mut backup_job = BackupJob::new(config, HashRegistry::new());
+        backup_job.run().unwrap();
+
+        // Only the large file is recorded as chunked
+        let snapshot = snapshot::find_snapshot(dest_dir.path(), None).unwrap();
+        assert!(snapshot.files[0].chunked);
+        assert!(snapshot.files[0].object.starts_with(store::CHUNK_LISTS_DIR));
+        assert!(!snapshot.files[1].chunked);
+        assert!(snapshot.files[1].object.starts_with(store::OBJECTS_DIR));
+
+        // Both files are restored intact
+        let target_dir = TempDir::new().unwrap();
+        crate::restore::restore_snapshot(&snapshot, dest_dir.path(), target_dir.path(), true).unwrap();
+        assert_eq!(fs::read(target_dir.path().join("disk.vhdx")).unwrap(), large_content);
+        assert_eq!(fs::read(target_dir.path().join("small.txt")).unwrap(), b"Small file");
+    }
+
+    #[test]
+    fn test_backup_job_packed_layout() {
+        // Create source and destination directories
+        let source_dir = TempDir::new().unwrap();
+        let dest_dir = TempDir::new().unwrap();
+        let hash_file = NamedTempFile::new().unwrap();
+
+        // Many small files and one file too large to pack
+        for i in 0..20 {
+            fs::write(source_dir.path().join(format!("small_{}.txt", i)), format!("File {}", i)).unwrap();
+        }
+        let large_content = vec![7u8; store::PACKING_THRESHOLD as usize + 1];
+        fs::write(source_dir.path().join("large.bin"), &large_content).unwrap();
+
+        let config = Config {
+            source_path: Some(source_dir.path().to_path_buf()),
+            destination_path: Some(dest_dir.path().to_path_buf()),
+            hash_file_path: Some(hash_file.path().to_path_buf()),
+            storage_layout: StorageLayout::Packed,
+            ..Config::default()
+        };
+        let mut backup_job = BackupJob::new(config, HashRegistry::new());
+        backup_job.run().unwrap();
+
+        // The small files share one pack and only the large file is a loose object
+        let pack_files = fs::read_dir(dest_dir.path().join(pack::PACKS_DIR)).unwrap().count();
+        assert_eq!(pack_files, 2); // One pack plus the index
+        let snapshot = snapshot::find_snapshot(dest_dir.path(), None).unwrap();
+        assert_eq!(snapshot.files.iter().filter(|entry| entry.packed).count(), 20);
+        assert!(snapshot.files[0].object.starts_with(store::OBJECTS_DIR));
+
+        // Restore and verify read straight out of the pack
+        let target_dir = TempDir::new().unwrap();
+        crate::restore::restore_snapshot(&snapshot, dest_dir.path(), target_dir.path(), true).unwrap();
+        assert_eq!(fs::read(target_dir.path().join("small_7.txt")).unwrap(), b"File 7");
+        assert_eq!(fs::read(target_dir.path().join("large.bin")).unwrap(), large_content);
+        let report = crate::restore::verify_snapshot(&snapshot, dest_dir.path()).unwrap();
+        assert_eq!(report.verified, 21);
+    }
+}
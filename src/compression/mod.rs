@@ -1,23 +1,273 @@
-use anyhow::Result;
-use std::fs::{self, File};
-use std::io::Write;
-use std::path::Path;
-use zstd::stream::{copy_decode, copy_encode};
+use crate::config::Durability;
+use crate::hashing::{FileStamp, HashingReader};
+use crate::report::Operation;
+use crate::throttle;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use walkdir::WalkDir;
+use zstd::stream::{copy_decode, copy_encode, decode_all, encode_all, Encoder};
 
 const COMPRESSION_LEVEL: i32 = 3; // Balanced between speed and size
 
-pub fn compress_file<P: AsRef<Path>, Q: AsRef<Path>>(source: P, destination: Q) -> Result<()> {
+/// Extension of files that are still being written and haven't been renamed into place
+pub const TEMP_EXTENSION: &str = "mbbut-tmp";
+
+/// Extension of the checkpoint that lets an interrupted run pick up a partial file where it
+/// left off
+pub const CHECKPOINT_EXTENSION: &str = "mbbut-checkpoint";
+
+/// Files at least this large are compressed in frames that an interrupted run can resume from
+pub const RESUMABLE_THRESHOLD: u64 = 256 * 1024 * 1024;
+/// Uncompressed bytes per independent zstd frame of a resumable file
+const FRAME_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Progress through a resumable file, saved after every completed frame
+#[derive(Debug, Serialize, Deserialize)]
+struct FrameCheckpoint {
+    /// The source as it was when compression started, so a changed source starts over
+    stamp: FileStamp,
+    /// Source bytes compressed so far
+    source_offset: u64,
+    /// Length of the partial file holding those bytes
+    compressed_length: u64,
+}
+
+/// Distinguishes temporary files written by concurrent workers
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Unique temporary file next to `path`
+pub fn temp_path_for(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().map(OsString::from).unwrap_or_default();
+    file_name.push(format!(
+        ".{}-{}.{}",
+        process::id(),
+        TEMP_COUNTER.fetch_add(1, Ordering::Relaxed),
+        TEMP_EXTENSION
+    ));
+    path.with_file_name(file_name)
+}
+
+/// Flush a finished file to disk if the durability policy asks for it
+fn sync_file(file: &File, durability: Durability) -> Result<()> {
+    if durability != Durability::None {
+        file.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Flush the directory holding `path` so a rename into it is durable, if the policy asks for it
+fn sync_parent_dir(path: &Path, durability: Durability) -> Result<()> {
+    // Directories can only be opened for syncing on Unix
+    if cfg!(unix) && durability == Durability::Full {
+        if let Some(parent) = path.parent() {
+            File::open(parent)?.sync_all()?;
+        }
+    }
+    Ok(())
+}
+
+/// Move a finished temporary file over `destination`
+pub fn rename_into_place(temp_file: &Path, destination: &Path, durability: Durability) -> Result<()> {
+    fs::rename(temp_file, destination)?;
+    sync_parent_dir(destination, durability)
+}
+
+/// Temporary file that a resumable compression into `destination` is written to. Unlike
+/// other temporary files its name is stable, so the next run can find it.
+pub fn partial_path_for(destination: &Path) -> PathBuf {
+    let mut file_name = destination.file_name().map(OsString::from).unwrap_or_default();
+    file_name.push(format!(".partial.{}", TEMP_EXTENSION));
+    destination.with_file_name(file_name)
+}
+
+/// Compress `source` into `partial` as a series of independent zstd frames of `frame_size`
+/// bytes, saving a checkpoint after each one. If a checkpoint shows an earlier attempt got
+/// partway through the same unchanged source, compression continues after its last frame. The
+/// finished part of the source is read again to hash it, which is much cheaper than
+/// compressing it again.
+fn compress_in_frames(source: &Path, partial: &Path, frame_size: u64, durability: Durability) -> Result<String> {
+    let stamp = FileStamp::from_path(source).context(Operation::Read)?;
+    let checkpoint_file = partial.with_extension(CHECKPOINT_EXTENSION);
+
+    let checkpoint = fs::read_to_string(&checkpoint_file)
+        .ok()
+        .and_then(|content| serde_json::from_str::<FrameCheckpoint>(&content).ok())
+        .filter(|checkpoint| checkpoint.stamp == stamp)
+        .filter(|checkpoint| {
+            fs::metadata(partial).is_ok_and(|metadata| metadata.len() >= checkpoint.compressed_length)
+        });
+    let (source_offset, compressed_length) = match &checkpoint {
+        Some(checkpoint) => (checkpoint.source_offset, checkpoint.compressed_length),
+        None => (0, 0),
+    };
+
+    let mut output = OpenOptions::new().create(true).write(true).truncate(false).open(partial)?;
+    // Drop whatever was written after the last completed frame
+    output.set_len(compressed_length)?;
+    output.seek(SeekFrom::End(0))?;
+
+    let mut reader = HashingReader::new(throttle::open(source)?);
+    io::copy(&mut reader.by_ref().take(source_offset), &mut io::sink())?;
+
+    let mut checkpoint = FrameCheckpoint {
+        stamp,
+        source_offset,
+        compressed_length,
+    };
+    loop {
+        let mut encoder = Encoder::new(throttle::writer(&mut output), COMPRESSION_LEVEL)?;
+        let copied = io::copy(&mut reader.by_ref().take(frame_size), &mut encoder).context(Operation::Compress)?;
+        // An empty source still gets one frame, so the output is valid zstd data
+        if copied == 0 && checkpoint.compressed_length > 0 {
+            break;
+        }
+        encoder.finish().context(Operation::Compress)?;
+        sync_file(&output, durability)?;
+
+        checkpoint.source_offset += copied;
+        checkpoint.compressed_length = output.stream_position()?;
+        let content = serde_json::to_string(&checkpoint)?;
+        write_atomically(&checkpoint_file, content.as_bytes(), durability)?;
+
+        if copied < frame_size {
+            break;
+        }
+    }
+
+    // The file is complete, so there is nothing left to resume
+    fs::remove_file(&checkpoint_file)?;
+    Ok(reader.hash())
+}
+
+/// Compress `source` into a new temporary file next to `destination`, returning the temporary
+/// file and the BLAKE3 hash of the source. The temporary file is removed if compression fails,
+/// except for large files, which are compressed resumably into a partial file that is kept so
+/// the next attempt can continue it.
+pub fn compress_to_temp(source: &Path, destination: &Path, durability: Durability) -> Result<(PathBuf, String)> {
     // Ensure the destination directory exists
-    if let Some(parent) = destination.as_ref().parent() {
+    if let Some(parent) = destination.parent() {
         fs::create_dir_all(parent)?;
     }
 
-    let source_file = File::open(source)?;
-    let destination_file = File::create(destination)?;
+    if fs::metadata(source).context(Operation::Read)?.len() >= RESUMABLE_THRESHOLD {
+        let partial = partial_path_for(destination);
+        let hash = compress_in_frames(source, &partial, FRAME_SIZE, durability)?;
+        return Ok((partial, hash));
+    }
 
-    copy_encode(source_file, destination_file, COMPRESSION_LEVEL)?;
+    let temp_file = temp_path_for(destination);
+    let result = throttle::open(source).and_then(|file| {
+        let mut reader = HashingReader::new(file);
+        let mut temp = throttle::writer(File::create(&temp_file)?);
+        copy_encode(&mut reader, &mut temp, COMPRESSION_LEVEL).context(Operation::Compress)?;
+        sync_file(temp.get_ref(), durability)?;
+        Ok(reader.hash())
+    });
 
-    Ok(())
+    match result {
+        Ok(hash) => Ok((temp_file, hash)),
+        Err(e) => {
+            let _ = fs::remove_file(&temp_file);
+            Err(e)
+        }
+    }
+}
+
+/// Compress `source` into `destination` and return the BLAKE3 hash of the source, reading it
+/// only once so the hash describes exactly the bytes that were compressed. The data is written
+/// to a temporary file first, so `destination` never holds a partially written file.
+pub fn compress_file<P: AsRef<Path>, Q: AsRef<Path>>(
+    source: P,
+    destination: Q,
+    durability: Durability,
+) -> Result<String> {
+    let destination = destination.as_ref();
+    let (temp_file, hash) = compress_to_temp(source.as_ref(), destination, durability)?;
+    if let Err(e) = rename_into_place(&temp_file, destination, durability) {
+        let _ = fs::remove_file(&temp_file);
+        return Err(e);
+    }
+
+    Ok(hash)
+}
+
+/// Write `data` to `path` through a temporary file that is renamed into place
+pub fn write_atomically(path: &Path, data: &[u8], durability: Durability) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let temp_file = temp_path_for(path);
+    let result = File::create(&temp_file)
+        .map_err(anyhow::Error::from)
+        .and_then(|mut file| {
+            throttle::writer(&mut file).write_all(data)?;
+            sync_file(&file, durability)
+        })
+        .and_then(|_| rename_into_place(&temp_file, path, durability));
+
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_file);
+    }
+    result
+}
+
+/// Open the log of JSON lines at `path` for appending, creating it if needed. If a crash cut
+/// the last line short, a newline is added so the next line starts fresh and only the torn one
+/// fails to parse.
+pub fn open_line_log(path: &Path) -> Result<File> {
+    let mut file = OpenOptions::new().create(true).read(true).append(true).open(path)?;
+    if file.metadata()?.len() > 0 {
+        let mut last_byte = [0; 1];
+        file.seek(SeekFrom::End(-1))?;
+        file.read_exact(&mut last_byte)?;
+        if last_byte[0] != b'\n' {
+            file.write_all(b"\n")?;
+        }
+    }
+    Ok(file)
+}
+
+/// Delete temporary files left in `root` by a run that was interrupted, returning how many
+/// were removed. Partial files with a checkpoint are kept for the next attempt to resume if
+/// they are in `resumable`, the partial files of the files this run will back up.
+pub fn remove_temp_files(root: &Path, resumable: &HashSet<PathBuf>) -> Result<usize> {
+    if !root.exists() {
+        return Ok(0);
+    }
+
+    let mut removed = 0;
+    for entry in WalkDir::new(root).follow_links(false) {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+
+        let path = entry.path();
+        let is_leftover = match path.extension().and_then(|e| e.to_str()) {
+            Some(TEMP_EXTENSION) => {
+                !resumable.contains(path) || !path.with_extension(CHECKPOINT_EXTENSION).exists()
+            }
+            Some(CHECKPOINT_EXTENSION) => {
+                let partial = path.with_extension(TEMP_EXTENSION);
+                !resumable.contains(&partial) || !partial.exists()
+            }
+            _ => false,
+        };
+        if is_leftover {
+            fs::remove_file(path)?;
+            removed += 1;
+        }
+    }
+
+    Ok(removed)
 }
 
 pub fn decompress_file<P: AsRef<Path>, Q: AsRef<Path>>(source: P, destination: Q) -> Result<()> {
@@ -34,10 +284,40 @@ pub fn decompress_file<P: AsRef<Path>, Q: AsRef<Path>>(source: P, destination: Q
     Ok(())
 }
 
+/// Decompress zstd data from `source` into `destination`
+pub fn decompress_stream<R: Read, W: Write>(source: R, destination: W) -> Result<()> {
+    copy_decode(source, destination)?;
+    Ok(())
+}
+
+/// Bytes read from the start of a file to estimate how well it compresses
+const SAMPLE_SIZE: u64 = 256 * 1024;
+
+/// Estimate the compressed size of the `size` byte file at `path` by compressing a sample from
+/// its start
+pub fn estimate_compressed_size(path: &Path, size: u64) -> Result<u64> {
+    let mut sample = Vec::new();
+    File::open(path)?.take(SAMPLE_SIZE).read_to_end(&mut sample)?;
+    if sample.is_empty() {
+        return Ok(0);
+    }
+
+    let compressed = compress_bytes(&sample)?;
+    let ratio = compressed.len() as f64 / sample.len() as f64;
+    Ok((size as f64 * ratio).round() as u64)
+}
+
+pub fn compress_bytes(data: &[u8]) -> Result<Vec<u8>> {
+    encode_all(data, COMPRESSION_LEVEL).context(Operation::Compress)
+}
+
+pub fn decompress_bytes(data: &[u8]) -> Result<Vec<u8>> {
+    Ok(decode_all(data)?)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::io::Read;
     use tempfile::{NamedTempFile, tempdir};
 
     #[test]
@@ -50,7 +330,7 @@ mod tests {
         let dest_path = temp_dir.path().join("empty.zst");
         
         // Compress the empty file
-        compress_file(source_file.path(), &dest_path).unwrap();
+        compress_file(source_file.path(), &dest_path, Durability::File).unwrap();
         
         // Verify the compressed file exists and is not empty (zstd adds headers)
         assert!(dest_path.exists());
@@ -71,7 +351,7 @@ mod tests {
         let dest_path = temp_dir.path().join("text.zst");
         
         // Compress the text file
-        compress_file(source_file.path(), &dest_path).unwrap();
+        compress_file(source_file.path(), &dest_path, Durability::File).unwrap();
         
         // Verify the compressed file exists and is smaller than the original
         // (text should compress well)
@@ -100,7 +380,7 @@ mod tests {
         let dest_path = temp_dir.path().join("binary.zst");
         
         // Compress the binary file
-        compress_file(source_file.path(), &dest_path).unwrap();
+        compress_file(source_file.path(), &dest_path, Durability::File).unwrap();
         
         // Verify the compressed file exists
         assert!(dest_path.exists());
@@ -117,7 +397,7 @@ mod tests {
         // Compress the file
         let temp_dir = tempdir().unwrap();
         let compressed_path = temp_dir.path().join("compressed.zst");
-        compress_file(source_file.path(), &compressed_path).unwrap();
+        compress_file(source_file.path(), &compressed_path, Durability::File).unwrap();
         
         // Decompress the file
         let decompressed_path = temp_dir.path().join("decompressed.txt");
@@ -131,6 +411,28 @@ mod tests {
         assert_eq!(decompressed_content, original_content);
     }
     
+    #[test]
+    fn test_compress_file() {
+        // Create a source file with content
+        let mut source_file = NamedTempFile::new().unwrap();
+        let original_content = "Hashed and compressed in one pass.".repeat(500);
+        source_file.write_all(original_content.as_bytes()).unwrap();
+        source_file.flush().unwrap();
+
+        // Compress the file and collect its hash
+        let temp_dir = tempdir().unwrap();
+        let compressed_path = temp_dir.path().join("compressed.zst");
+        let hash = compress_file(source_file.path(), &compressed_path, Durability::File).unwrap();
+
+        // The hash matches hashing the file separately
+        assert_eq!(hash, crate::hashing::hash_file(source_file.path()).unwrap());
+
+        // The compressed file decompresses to the original content
+        let decompressed_path = temp_dir.path().join("decompressed.txt");
+        decompress_file(&compressed_path, &decompressed_path).unwrap();
+        assert_eq!(fs::read_to_string(&decompressed_path).unwrap(), original_content);
+    }
+
     #[test]
     fn test_decompress_nonexistent_file() {
         // Try to decompress a non-existent file
@@ -169,9 +471,195 @@ mod tests {
         let nested_path = temp_dir.path().join("nested/dirs/that/dont/exist/yet.zst");
         
         // Compression should create all parent directories
-        compress_file(source_file.path(), &nested_path).unwrap();
+        compress_file(source_file.path(), &nested_path, Durability::Full).unwrap();
         
         // Verify the compressed file exists, meaning the directories were created
         assert!(nested_path.exists());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_compress_bytes_round_trip() {
+        let original = b"In-memory content that should survive a round trip.".repeat(20);
+
+        let compressed = compress_bytes(&original).unwrap();
+        assert!(compressed.len() < original.len());
+
+        let decompressed = decompress_bytes(&compressed).unwrap();
+        assert_eq!(decompressed, original);
+    }
+
+    #[test]
+    fn test_decompress_bytes_invalid_data() {
+        assert!(decompress_bytes(b"This is not valid zstd data").is_err());
+    }
+
+    #[test]
+    fn test_compress_file_leaves_no_temp_files() {
+        let mut source_file = NamedTempFile::new().unwrap();
+        source_file.write_all(b"Written through a temporary file").unwrap();
+        source_file.flush().unwrap();
+
+        // Every durability policy ends with only the final file in place
+        let temp_dir = tempdir().unwrap();
+        for (name, durability) in [
+            ("none.zst", Durability::None),
+            ("file.zst", Durability::File),
+            ("full.zst", Durability::Full),
+        ] {
+            compress_file(source_file.path(), temp_dir.path().join(name), durability).unwrap();
+        }
+
+        let mut names: Vec<_> = fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["file.zst", "full.zst", "none.zst"]);
+    }
+
+    #[test]
+    fn test_compress_file_failure_keeps_existing_destination() {
+        let temp_dir = tempdir().unwrap();
+        let dest_path = temp_dir.path().join("existing.zst");
+        fs::write(&dest_path, b"previous backup").unwrap();
+
+        // A source that can't be read leaves the old copy and no temporary file behind
+        let missing = temp_dir.path().join("missing.txt");
+        assert!(compress_file(&missing, &dest_path, Durability::File).is_err());
+        assert_eq!(fs::read(&dest_path).unwrap(), b"previous backup");
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_remove_temp_files() {
+        let temp_dir = tempdir().unwrap();
+        let kept = temp_dir.path().join("nested/kept.txt.zst");
+        let leftover = temp_path_for(&kept);
+        fs::create_dir_all(kept.parent().unwrap()).unwrap();
+        fs::write(&kept, b"finished").unwrap();
+        fs::write(&leftover, b"interrupted").unwrap();
+
+        // Only the unfinished temporary file is removed
+        assert_eq!(remove_temp_files(temp_dir.path(), &HashSet::new()).unwrap(), 1);
+        assert!(kept.exists());
+        assert!(!leftover.exists());
+
+        // A missing root has nothing to clean up
+        assert_eq!(remove_temp_files(&temp_dir.path().join("missing"), &HashSet::new()).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_compress_in_frames() {
+        let mut source_file = NamedTempFile::new().unwrap();
+        let content: Vec<u8> = (0..10_000u32).flat_map(|i| i.to_le_bytes()).collect();
+        source_file.write_all(&content).unwrap();
+        source_file.flush().unwrap();
+
+        let temp_dir = tempdir().unwrap();
+        let partial = partial_path_for(&temp_dir.path().join("large.bin.zst"));
+        let hash = compress_in_frames(source_file.path(), &partial, 4096, Durability::File).unwrap();
+
+        // The frames decompress as one stream, and the checkpoint is gone once done
+        assert_eq!(hash, crate::hashing::hash_file(source_file.path()).unwrap());
+        assert_eq!(decompress_bytes(&fs::read(&partial).unwrap()).unwrap(), content);
+        assert!(!partial.with_extension(CHECKPOINT_EXTENSION).exists());
+    }
+
+    #[test]
+    fn test_compress_in_frames_resumes_after_last_frame() {
+        let mut source_file = NamedTempFile::new().unwrap();
+        let content: Vec<u8> = (0..10_000u32).flat_map(|i| i.to_le_bytes()).collect();
+        source_file.write_all(&content).unwrap();
+        source_file.flush().unwrap();
+
+        // An interrupted run finished two frames and started on a third
+        let temp_dir = tempdir().unwrap();
+        let partial = partial_path_for(&temp_dir.path().join("large.bin.zst"));
+        let mut finished = encode_all(&content[..4096], 19).unwrap();
+        finished.extend(encode_all(&content[4096..8192], 19).unwrap());
+        let mut written = finished.clone();
+        written.extend(b"torn frame");
+        fs::write(&partial, &written).unwrap();
+        let checkpoint = FrameCheckpoint {
+            stamp: FileStamp::from_path(source_file.path()).unwrap(),
+            source_offset: 8192,
+            compressed_length: finished.len() as u64,
+        };
+        let checkpoint_file = partial.with_extension(CHECKPOINT_EXTENSION);
+        fs::write(&checkpoint_file, serde_json::to_string(&checkpoint).unwrap()).unwrap();
+
+        // The leftover sweep keeps the partial file because this run will resume it
+        assert_eq!(remove_temp_files(temp_dir.path(), &HashSet::from([partial.clone()])).unwrap(), 0);
+
+        // Resuming keeps the finished frames and replaces the torn one
+        let hash = compress_in_frames(source_file.path(), &partial, 4096, Durability::File).unwrap();
+        let compressed = fs::read(&partial).unwrap();
+        assert_eq!(&compressed[..finished.len()], &finished[..]);
+        assert_eq!(decompress_bytes(&compressed).unwrap(), content);
+        assert_eq!(hash, crate::hashing::hash_file(source_file.path()).unwrap());
+    }
+
+    #[test]
+    fn test_compress_in_frames_restarts_for_changed_source() {
+        let mut source_file = NamedTempFile::new().unwrap();
+        source_file.write_all(&[7; 10_000]).unwrap();
+        source_file.flush().unwrap();
+
+        // A checkpoint recorded for an older version of the source
+        let temp_dir = tempdir().unwrap();
+        let partial = partial_path_for(&temp_dir.path().join("large.bin.zst"));
+        fs::write(&partial, b"frames of the old version").unwrap();
+        let checkpoint = FrameCheckpoint {
+            stamp: FileStamp {
+                size: 5,
+                modified: std::time::Duration::from_secs(1),
+            },
+            source_offset: 4096,
+            compressed_length: 10,
+        };
+        let checkpoint_file = partial.with_extension(CHECKPOINT_EXTENSION);
+        fs::write(&checkpoint_file, serde_json::to_string(&checkpoint).unwrap()).unwrap();
+
+        compress_in_frames(source_file.path(), &partial, 4096, Durability::None).unwrap();
+        assert_eq!(decompress_bytes(&fs::read(&partial).unwrap()).unwrap(), vec![7; 10_000]);
+    }
+
+    #[test]
+    fn test_remove_temp_files_removes_orphaned_checkpoints() {
+        let temp_dir = tempdir().unwrap();
+        let partial = partial_path_for(&temp_dir.path().join("large.bin.zst"));
+        fs::write(partial.with_extension(CHECKPOINT_EXTENSION), b"{}").unwrap();
+
+        assert_eq!(remove_temp_files(temp_dir.path(), &HashSet::from([partial])).unwrap(), 1);
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_remove_temp_files_removes_partials_not_resumed() {
+        let temp_dir = tempdir().unwrap();
+        let partial = partial_path_for(&temp_dir.path().join("large.bin.zst"));
+        fs::write(&partial, b"frames").unwrap();
+        fs::write(partial.with_extension(CHECKPOINT_EXTENSION), b"{}").unwrap();
+
+        // The source was deleted or shrank, so this run won't pick the partial file up
+        assert_eq!(remove_temp_files(temp_dir.path(), &HashSet::new()).unwrap(), 2);
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_estimate_compressed_size() {
+        let temp_dir = tempdir().unwrap();
+
+        // Repetitive content is estimated to shrink a lot
+        let text_path = temp_dir.path().join("text.txt");
+        let text = "Very repetitive text. ".repeat(1000);
+        fs::write(&text_path, &text).unwrap();
+        let estimate = estimate_compressed_size(&text_path, text.len() as u64).unwrap();
+        assert!(estimate > 0 && estimate < text.len() as u64 / 10);
+
+        // Nothing to compress in an empty file
+        let empty_path = temp_dir.path().join("empty.txt");
+        fs::write(&empty_path, b"").unwrap();
+        assert_eq!(estimate_compressed_size(&empty_path, 0).unwrap(), 0);
+    }
+}
@@ -1,8 +1,126 @@
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 use std::fs;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+
+/// What happens to the backed up copy of a file once it's deleted from the source. Only the
+/// mirror layout has a copy per file to act on, the other layouts always keep it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeletionPolicy {
+    /// Leave the copy where it is
+    #[default]
+    Keep,
+    /// Move the copy into the attic folder in the destination
+    Attic,
+    /// Take the copy out of the mirror. It moves to the versions folder, where the snapshots
+    /// that still list the file restore it from.
+    Remove,
+}
+
+/// How compressed files are laid out in the destination
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageLayout {
+    /// Mirror the source tree, one `.zst` file per source file
+    #[default]
+    Mirror,
+    /// Store each distinct content once, named after its BLAKE3 hash
+    Objects,
+    /// Like `Objects`, but split large files into content-defined chunks that are stored once
+    Chunked,
+    /// Like `Objects`, but append small files to pack files instead of storing them one by one
+    Packed,
+}
+
+/// What happens to symbolic links found in the source
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SymlinkPolicy {
+    /// Record the link and where it points in the snapshot, and recreate it on restore
+    #[default]
+    Preserve,
+    /// Back up what the link points to as if it were in the link's place. Links that dangle
+    /// or point back up the tree they're in are preserved instead.
+    Follow,
+    /// Leave links out of the backup
+    Skip,
+}
+
+/// How far written files are flushed to disk before they are renamed into place
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Durability {
+    /// Leave flushing to the operating system
+    None,
+    /// Sync each file's contents before renaming it into place
+    #[default]
+    File,
+    /// Also sync the containing directory so the rename itself survives a power loss
+    Full,
+}
+
+/// How a file that fails with a transient I/O error is tried again
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Tries per file, including the first one
+    pub attempts: u32,
+    /// Pause before the first retry in milliseconds, doubled for every retry after it
+    pub backoff_ms: u64,
+    /// Kinds of I/O errors worth retrying, named as in run reports, e.g. `TimedOut`.
+    /// `InputOutput` is the EIO of failing disks and network shares.
+    pub retryable: Vec<String>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            backoff_ms: 500,
+            retryable: ["InputOutput", "WouldBlock", "TimedOut", "Interrupted"]
+                .into_iter()
+                .map(String::from)
+                .collect(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn is_retryable(&self, kind: &str) -> bool {
+        self.retryable.iter().any(|retryable| retryable == kind)
+    }
+
+    /// Pause before retry number `retry`, counting from 1
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let factor = 2u64.saturating_pow(retry.saturating_sub(1));
+        Duration::from_millis(self.backoff_ms.saturating_mul(factor))
+    }
+}
+
+fn default_changed_file_retries() -> u32 {
+    2
+}
+
+fn default_pack_target_size() -> u64 {
+    64 * 1024 * 1024
+}
+
+/// A folder backed up alongside `Config::source_path`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Source {
+    pub path: PathBuf,
+    /// Folder inside the destination the source is backed up to
+    pub prefix: PathBuf,
+    /// Directory names skipped in this source, on top of the global blacklist
+    #[serde(default)]
+    pub blacklist_dirs: HashSet<String>,
+    /// File extensions skipped in this source, on top of the global blacklist
+    #[serde(default)]
+    pub blacklist_extensions: HashSet<String>,
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
@@ -11,6 +129,38 @@ pub struct Config {
     pub source_path: Option<PathBuf>,
     pub destination_path: Option<PathBuf>,
     pub hash_file_path: Option<PathBuf>,
+    #[serde(default)]
+    pub deletion_policy: DeletionPolicy,
+    #[serde(default)]
+    pub storage_layout: StorageLayout,
+    /// Size in bytes a pack file may grow to before a new one is started
+    #[serde(default = "default_pack_target_size")]
+    pub pack_target_size: u64,
+    #[serde(default)]
+    pub durability: Durability,
+    /// Number of files processed at once, one per CPU core if unset
+    #[serde(default)]
+    pub workers: Option<usize>,
+    /// Cap on how many bytes per second are read, and separately written, if set
+    #[serde(default)]
+    pub max_bytes_per_second: Option<u64>,
+    /// Lower the CPU and I/O scheduling priority of the backup (Linux only)
+    #[serde(default)]
+    pub low_priority: bool,
+    /// Times a file that changes while it's read is read again before its copy is kept and
+    /// marked inconsistent
+    #[serde(default = "default_changed_file_retries")]
+    pub changed_file_retries: u32,
+    #[serde(default)]
+    pub symlinks: SymlinkPolicy,
+    #[serde(default)]
+    pub retry: RetryPolicy,
+    /// More folders to back up, each into its own folder in the destination
+    #[serde(default)]
+    pub sources: Vec<Source>,
+    /// Named jobs, each made of the settings above with its own settings on top
+    #[serde(default)]
+    pub jobs: BTreeMap<String, toml::Table>,
 }
 
 impl Default for Config {
@@ -32,6 +182,18 @@ impl Default for Config {
             source_path: None,
             destination_path: None,
             hash_file_path: None,
+            deletion_policy: DeletionPolicy::default(),
+            storage_layout: StorageLayout::default(),
+            pack_target_size: default_pack_target_size(),
+            durability: Durability::default(),
+            workers: None,
+            max_bytes_per_second: None,
+            low_priority: false,
+            changed_file_retries: default_changed_file_retries(),
+            symlinks: SymlinkPolicy::default(),
+            retry: RetryPolicy::default(),
+            sources: Vec::new(),
+            jobs: BTreeMap::new(),
         }
     }
 }
@@ -50,37 +212,204 @@ impl Config {
     }
 
     pub fn is_blacklisted(&self, path: &Path) -> bool {
-        // Check if any component of the path is in the blacklist
-        if let Some(file_name) = path.file_name() {
-            if let Some(file_name_str) = file_name.to_str() {
-                if self.blacklist_dirs.contains(file_name_str) {
-                    return true;
+        matches_blacklist(&self.blacklist_dirs, &self.blacklist_extensions, path)
+    }
+
+    /// Whether `path` in `source` is skipped, by the global blacklist or the source's own
+    pub fn is_excluded(&self, source: &Source, path: &Path) -> bool {
+        self.is_blacklisted(path)
+            || matches_blacklist(&source.blacklist_dirs, &source.blacklist_extensions, path)
+    }
+
+    /// Every folder to back up: `source_path`, backed up to the root of the destination, followed
+    /// by `sources`. No source may be backed up inside another's prefix, so `source_path`
+    /// can't be combined with `sources`.
+    pub fn sources(&self) -> Result<Vec<Source>> {
+        let mut sources = Vec::new();
+        if let Some(source_path) = &self.source_path {
+            sources.push(Source {
+                path: source_path.clone(),
+                prefix: PathBuf::new(),
+                blacklist_dirs: HashSet::new(),
+                blacklist_extensions: HashSet::new(),
+            });
+        }
+        sources.extend(self.sources.iter().cloned());
+
+        if sources.is_empty() {
+            bail!("Source path not set");
+        }
+        for source in &sources {
+            // The prefix is joined to the destination, so it must stay inside it
+            let escapes = source
+                .prefix
+                .components()
+                .any(|component| !matches!(component, Component::Normal(_)));
+            if escapes {
+                bail!(
+                    "Destination prefix {} of source {} must be a relative path without '..'",
+                    source.prefix.display(),
+                    source.path.display()
+                );
+            }
+        }
+        // A source backed up inside another's folder would mix its files, and its deletions,
+        // with the other's
+        for (i, outer) in sources.iter().enumerate() {
+            for (j, inner) in sources.iter().enumerate() {
+                if i == j || !inner.prefix.starts_with(&outer.prefix) {
+                    continue;
+                }
+                if outer.prefix.as_os_str().is_empty() {
+                    bail!(
+                        "Source {} is backed up to the root of the destination, which holds every other source; give each source its own prefix",
+                        outer.path.display()
+                    );
                 }
+                bail!(
+                    "Sources must have destination prefixes that don't overlap, {} of source {} is inside {} of source {}",
+                    inner.prefix.display(),
+                    inner.path.display(),
+                    outer.prefix.display(),
+                    outer.path.display()
+                );
             }
         }
+        Ok(sources)
+    }
 
-        // Check if the file extension is blacklisted
-        if let Some(extension) = path.extension() {
-            if let Some(ext_str) = extension.to_str() {
-                if self.blacklist_extensions.contains(ext_str) {
-                    return true;
+    /// Settings of the job called `name`: the settings shared by every job, overridden by the
+    /// job's own. Unless the job sets `hash_file_path`, it gets a registry of its own next to the
+    /// shared one, e.g. `hashes.photos.json` for `hashes.json`.
+    pub fn job(&self, name: &str) -> Result<Config> {
+        let overrides = self
+            .jobs
+            .get(name)
+            .with_context(|| format!("No job named '{}' in the configuration", name))?;
+        // The name ends up in the registry's file name
+        let valid_name = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid_name {
+            bail!("Job name '{}' may only contain letters, digits, '-' and '_'", name);
+        }
+
+        let mut settings = match toml::Value::try_from(self)? {
+            toml::Value::Table(settings) => settings,
+            _ => unreachable!("Config serializes to a table"),
+        };
+        settings.remove("jobs");
+        merge_settings(&mut settings, overrides.clone());
+
+        let mut config: Config = toml::Value::Table(settings)
+            .try_into()
+            .with_context(|| format!("Invalid settings for job '{}'", name))?;
+        if !overrides.contains_key("hash_file_path") {
+            config.hash_file_path = config
+                .hash_file_path
+                .map(|path| job_registry_path(&path, name));
+        }
+        Ok(config)
+    }
+
+    /// Settings of every job, by name. Jobs must not share a registry or a destination, whose
+    /// snapshots, reports and versions would get mixed up.
+    pub fn all_jobs(&self) -> Result<Vec<(String, Config)>> {
+        let mut jobs = Vec::new();
+        let mut registries = HashSet::new();
+        let mut destinations = HashSet::new();
+        for name in self.jobs.keys() {
+            let job = self.job(name)?;
+            if let Some(hash_file_path) = &job.hash_file_path {
+                if !registries.insert(hash_file_path.clone()) {
+                    bail!(
+                        "Jobs must keep separate registries, {} is used twice",
+                        hash_file_path.display()
+                    );
+                }
+            }
+            if let Some(destination_path) = &job.destination_path {
+                if !destinations.insert(destination_path.clone()) {
+                    bail!(
+                        "Jobs must have separate destinations, {} is used twice",
+                        destination_path.display()
+                    );
                 }
             }
+            jobs.push((name.clone(), job));
         }
+        Ok(jobs)
+    }
+}
 
-        // Check for any parent directories in the blacklist
-        for ancestor in path.ancestors().skip(1) {
-            if let Some(dir_name) = ancestor.file_name() {
-                if let Some(dir_name_str) = dir_name.to_str() {
-                    if self.blacklist_dirs.contains(dir_name_str) {
-                        return true;
-                    }
-                }
+/// Apply `overrides` on top of `settings`. Tables such as `[retry]` are merged key by key, any
+/// other value is replaced.
+fn merge_settings(settings: &mut toml::Table, overrides: toml::Table) {
+    for (key, value) in overrides {
+        match (settings.get_mut(&key), value) {
+            (Some(toml::Value::Table(existing)), toml::Value::Table(value)) => {
+                merge_settings(existing, value)
+            }
+            (_, value) => {
+                settings.insert(key, value);
             }
         }
+    }
+}
 
-        false
+/// Registry of the job called `name`, next to the registry at `shared_path`
+fn job_registry_path(shared_path: &Path, name: &str) -> PathBuf {
+    let stem = shared_path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let file_name = match shared_path.extension() {
+        Some(extension) => format!("{}.{}.{}", stem, name, extension.to_string_lossy()),
+        None => format!("{}.{}", stem, name),
+    };
+    shared_path.with_file_name(file_name)
+}
+
+fn matches_blacklist(dirs: &HashSet<String>, extensions: &HashSet<String>, path: &Path) -> bool {
+    // Check if any component of the path is in the blacklist
+    if let Some(file_name) = path.file_name() {
+        if let Some(file_name_str) = file_name.to_str() {
+            if dirs.contains(file_name_str) {
+                return true;
+            }
+        }
     }
+
+    // Check if the file extension is blacklisted
+    if let Some(extension) = path.extension() {
+        if let Some(ext_str) = extension.to_str() {
+            if extensions.contains(ext_str) {
+                return true;
+            }
+        }
+    }
+
+    // Check for any parent directories in the blacklist
+    for ancestor in path.ancestors().skip(1) {
+        if let Some(dir_name) = ancestor.file_name() {
+            if let Some(dir_name_str) = dir_name.to_str() {
+                if dirs.contains(dir_name_str) {
+                    return true;
+                }
+            }
+        }
+    }
+
+    false
+}
+
+/// The source `path` belongs to. When sources are nested, the innermost one owns it.
+pub fn source_for<'a>(sources: &'a [Source], path: &Path) -> Option<&'a Source> {
+    sources
+        .iter()
+        .filter(|source| path.starts_with(&source.path))
+        .max_by_key(|source| source.path.components().count())
 }
 
 #[cfg(test)]
@@ -110,6 +439,30 @@ mod tests {
         assert!(config.source_path.is_none());
         assert!(config.destination_path.is_none());
         assert!(config.hash_file_path.is_none());
+
+        // Verify deleted files are kept by default
+        assert_eq!(config.deletion_policy, DeletionPolicy::Keep);
+
+        // Verify the destination mirrors the source by default
+        assert_eq!(config.storage_layout, StorageLayout::Mirror);
+        assert_eq!(config.pack_target_size, 64 * 1024 * 1024);
+
+        // Verify files are synced before they are renamed into place by default
+        assert_eq!(config.durability, Durability::File);
+
+        // Verify nothing is throttled by default
+        assert_eq!(config.workers, None);
+        assert_eq!(config.max_bytes_per_second, None);
+        assert!(!config.low_priority);
+        assert_eq!(config.changed_file_retries, 2);
+
+        // Verify symlinks are kept as links by default
+        assert_eq!(config.symlinks, SymlinkPolicy::Preserve);
+
+        // Verify transient errors are retried twice by default
+        assert_eq!(config.retry.attempts, 3);
+        assert!(config.retry.is_retryable("InputOutput"));
+        assert!(!config.retry.is_retryable("NotFound"));
     }
 
     #[test]
@@ -140,6 +493,292 @@ mod tests {
         assert_eq!(config.source_path, Some(PathBuf::from("/tmp/source")));
         assert_eq!(config.destination_path, Some(PathBuf::from("/tmp/destination")));
         assert_eq!(config.hash_file_path, Some(PathBuf::from("/tmp/hashes.json")));
+
+        // Options missing from the file fall back to their defaults
+        assert_eq!(config.deletion_policy, DeletionPolicy::Keep);
+    }
+
+    #[test]
+    fn test_config_load_deletion_policy() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        let toml_content = r#"
+            blacklist_dirs = []
+            blacklist_extensions = []
+            deletion_policy = "attic"
+        "#;
+        temp_file.write_all(toml_content.as_bytes()).unwrap();
+
+        let config = Config::load_from_file(temp_file.path()).unwrap();
+        assert_eq!(config.deletion_policy, DeletionPolicy::Attic);
+    }
+
+    #[test]
+    fn test_config_load_durability() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        let toml_content = r#"
+            blacklist_dirs = []
+            blacklist_extensions = []
+            durability = "full"
+        "#;
+        temp_file.write_all(toml_content.as_bytes()).unwrap();
+
+        let config = Config::load_from_file(temp_file.path()).unwrap();
+        assert_eq!(config.durability, Durability::Full);
+
+        let durability: Durability = toml::Value::from("none").try_into().unwrap();
+        assert_eq!(durability, Durability::None);
+    }
+
+    #[test]
+    fn test_config_load_throttling() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        let toml_content = r#"
+            blacklist_dirs = []
+            blacklist_extensions = []
+            workers = 2
+            max_bytes_per_second = 10485760
+            low_priority = true
+        "#;
+        temp_file.write_all(toml_content.as_bytes()).unwrap();
+
+        let config = Config::load_from_file(temp_file.path()).unwrap();
+        assert_eq!(config.workers, Some(2));
+        assert_eq!(config.max_bytes_per_second, Some(10 * 1024 * 1024));
+        assert!(config.low_priority);
+    }
+
+    #[test]
+    fn test_config_load_retry_policy() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        let toml_content = r#"
+            blacklist_dirs = []
+            blacklist_extensions = []
+
+            [retry]
+            attempts = 5
+            retryable = ["TimedOut"]
+        "#;
+        temp_file.write_all(toml_content.as_bytes()).unwrap();
+
+        let config = Config::load_from_file(temp_file.path()).unwrap();
+        assert_eq!(config.retry.attempts, 5);
+        assert!(config.retry.is_retryable("TimedOut"));
+        assert!(!config.retry.is_retryable("InputOutput"));
+
+        // Settings missing from the section keep their defaults, and the backoff doubles
+        assert_eq!(config.retry.backoff(1), Duration::from_millis(500));
+        assert_eq!(config.retry.backoff(3), Duration::from_millis(2000));
+    }
+
+    #[test]
+    fn test_config_load_storage_layout() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        let toml_content = r#"
+            blacklist_dirs = []
+            blacklist_extensions = []
+            storage_layout = "objects"
+            pack_target_size = 1048576
+        "#;
+        temp_file.write_all(toml_content.as_bytes()).unwrap();
+
+        let config = Config::load_from_file(temp_file.path()).unwrap();
+        assert_eq!(config.storage_layout, StorageLayout::Objects);
+        assert_eq!(config.pack_target_size, 1024 * 1024);
+
+        let layout: StorageLayout = toml::Value::from("chunked").try_into().unwrap();
+        assert_eq!(layout, StorageLayout::Chunked);
+        let layout: StorageLayout = toml::Value::from("packed").try_into().unwrap();
+        assert_eq!(layout, StorageLayout::Packed);
+    }
+
+    #[test]
+    fn test_config_load_sources() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        let toml_content = r#"
+            blacklist_dirs = []
+            blacklist_extensions = []
+
+            [[sources]]
+            path = "/home/user"
+            prefix = "home"
+
+            [[sources]]
+            path = "/mnt/photos"
+            prefix = "photos"
+            blacklist_extensions = ["xmp"]
+
+            [[sources]]
+            path = "/home/user/projects"
+            prefix = "work/projects"
+        "#;
+        temp_file.write_all(toml_content.as_bytes()).unwrap();
+
+        let config = Config::load_from_file(temp_file.path()).unwrap();
+        let sources = config.sources().unwrap();
+        assert_eq!(sources.len(), 3);
+
+        assert_eq!(sources[0].path, PathBuf::from("/home/user"));
+        assert_eq!(sources[0].prefix, PathBuf::from("home"));
+        assert_eq!(sources[1].prefix, PathBuf::from("photos"));
+
+        // Excludes of a source only apply to that source
+        assert!(config.is_excluded(&sources[1], Path::new("/mnt/photos/a.xmp")));
+        assert!(!config.is_excluded(&sources[0], Path::new("/home/user/a.xmp")));
+
+        // Nested sources own the files inside them
+        let owner = source_for(&sources, Path::new("/home/user/projects/main.rs")).unwrap();
+        assert_eq!(owner.prefix, PathBuf::from("work/projects"));
+        let owner = source_for(&sources, Path::new("/home/user/notes.txt")).unwrap();
+        assert_eq!(owner.prefix, PathBuf::from("home"));
+        assert!(source_for(&sources, Path::new("/etc/hosts")).is_none());
+    }
+
+    #[test]
+    fn test_config_sources_invalid() {
+        let source = |path: &str, prefix: &str| Source {
+            path: PathBuf::from(path),
+            prefix: PathBuf::from(prefix),
+            blacklist_dirs: HashSet::new(),
+            blacklist_extensions: HashSet::new(),
+        };
+
+        assert!(Config::default().sources().is_err());
+
+        // Prefixes must stay inside the destination
+        for prefix in ["/photos", "../photos", "photos/../.."] {
+            let config = Config {
+                sources: vec![source("/mnt/photos", prefix)],
+                ..Config::default()
+            };
+            assert!(config.sources().is_err(), "{} was accepted", prefix);
+        }
+
+        // Two sources can't be backed up to the same folder
+        let config = Config {
+            source_path: Some(PathBuf::from("/home/user")),
+            sources: vec![source("/mnt/photos", "")],
+            ..Config::default()
+        };
+        assert!(config.sources().is_err());
+
+        // The legacy source path goes to the root of the destination, which holds every prefix
+        let config = Config {
+            source_path: Some(PathBuf::from("/home/user")),
+            sources: vec![source("/mnt/photos", "photos")],
+            ..Config::default()
+        };
+        assert!(config.sources().is_err());
+
+        // Nor can one source be backed up inside another's folder, in either order
+        let config = Config {
+            sources: vec![source("/mnt/photos", "media"), source("/mnt/music", "media/music")],
+            ..Config::default()
+        };
+        assert!(config.sources().is_err());
+        let config = Config {
+            sources: vec![source("/mnt/music", "media/music"), source("/mnt/photos", "media")],
+            ..Config::default()
+        };
+        assert!(config.sources().is_err());
+
+        // Prefixes that only share the start of a name don't overlap
+        let config = Config {
+            sources: vec![source("/mnt/photos", "media"), source("/mnt/music", "media2/music")],
+            ..Config::default()
+        };
+        assert_eq!(config.sources().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_config_jobs() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        let toml_content = r#"
+            blacklist_dirs = ["node_modules"]
+            blacklist_extensions = []
+            hash_file_path = "/var/lib/mbbut/hashes.json"
+            durability = "full"
+
+            [retry]
+            attempts = 5
+
+            [jobs.photos]
+            source_path = "/mnt/photos"
+            destination_path = "/backup/photos"
+            storage_layout = "objects"
+
+            [jobs.code]
+            source_path = "/home/user/code"
+            destination_path = "/backup/code"
+            hash_file_path = "/home/user/.mbbut/code.json"
+            blacklist_dirs = ["target"]
+
+            [jobs.code.retry]
+            backoff_ms = 100
+        "#;
+        temp_file.write_all(toml_content.as_bytes()).unwrap();
+        let config = Config::load_from_file(temp_file.path()).unwrap();
+
+        // Jobs share the global settings unless they override them
+        let photos = config.job("photos").unwrap();
+        assert_eq!(photos.source_path, Some(PathBuf::from("/mnt/photos")));
+        assert_eq!(photos.storage_layout, StorageLayout::Objects);
+        assert_eq!(photos.durability, Durability::Full);
+        assert!(photos.blacklist_dirs.contains("node_modules"));
+        assert!(photos.jobs.is_empty());
+
+        // Tables are merged key by key, other values replaced
+        let code = config.job("code").unwrap();
+        assert_eq!(code.retry.attempts, 5);
+        assert_eq!(code.retry.backoff_ms, 100);
+        assert_eq!(code.blacklist_dirs, HashSet::from(["target".to_string()]));
+
+        // Each job keeps its own registry
+        assert_eq!(photos.hash_file_path, Some(PathBuf::from("/var/lib/mbbut/hashes.photos.json")));
+        assert_eq!(code.hash_file_path, Some(PathBuf::from("/home/user/.mbbut/code.json")));
+
+        let names: Vec<String> = config.all_jobs().unwrap().into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, vec!["code", "photos"]);
+        assert!(config.job("music").is_err());
+    }
+
+    #[test]
+    fn test_config_jobs_invalid() {
+        let job = |settings: &str| -> toml::Table { toml::from_str(settings).unwrap() };
+
+        // Names end up in file names
+        let config = Config {
+            jobs: BTreeMap::from([("../photos".to_string(), job("source_path = '/mnt/photos'"))]),
+            ..Config::default()
+        };
+        assert!(config.job("../photos").is_err());
+
+        // Settings of the wrong type
+        let config = Config {
+            jobs: BTreeMap::from([("photos".to_string(), job("workers = 'many'"))]),
+            ..Config::default()
+        };
+        assert!(config.job("photos").is_err());
+
+        // Two jobs sharing a registry
+        let config = Config {
+            jobs: BTreeMap::from([
+                ("a".to_string(), job("hash_file_path = '/tmp/hashes.json'")),
+                ("b".to_string(), job("hash_file_path = '/tmp/hashes.json'")),
+            ]),
+            ..Config::default()
+        };
+        assert!(config.all_jobs().is_err());
+
+        // Two jobs inheriting the same destination
+        let config = Config {
+            destination_path: Some(PathBuf::from("/backup")),
+            jobs: BTreeMap::from([
+                ("a".to_string(), job("source_path = '/mnt/photos'")),
+                ("b".to_string(), job("source_path = '/mnt/music'")),
+            ]),
+            ..Config::default()
+        };
+        assert!(config.all_jobs().is_err());
     }
 
     #[test]
@@ -160,9 +799,11 @@ mod tests {
     #[test]
     fn test_config_save_to_file() {
         // Create a config to save
-        let mut config = Config::default();
-        config.source_path = Some(PathBuf::from("/test/source"));
-        config.destination_path = Some(PathBuf::from("/test/dest"));
+        let config = Config {
+            source_path: Some(PathBuf::from("/test/source")),
+            destination_path: Some(PathBuf::from("/test/dest")),
+            ..Config::default()
+        };
         
         // Save to a temporary file
         let temp_file = NamedTempFile::new().unwrap();
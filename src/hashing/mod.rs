@@ -1,11 +1,130 @@
+use crate::compression;
+use crate::config::Durability;
+use crate::metadata::FileMetadata;
+use crate::throttle;
 use anyhow::Result;
 use blake3::Hasher;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::fs::{self, File};
-use std::io::{BufReader, Read, Write};
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Write};
 use std::path::{Path, PathBuf};
 use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Size and modification time of a file at the moment it was hashed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileStamp {
+    pub size: u64,
+    pub modified: Duration,
+}
+
+impl FileStamp {
+    pub fn from_metadata(metadata: &fs::Metadata) -> Result<Self> {
+        let modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Ok(Self {
+            size: metadata.len(),
+            modified,
+        })
+    }
+
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_metadata(&fs::metadata(path)?)
+    }
+}
+
+/// Record of a tracked file that has disappeared from the source
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tombstone {
+    /// Seconds since the Unix epoch when the deletion was noticed
+    pub deleted_at: u64,
+    /// Hash of the last backed up version
+    pub hash: String,
+}
+
+/// Where a file that needs backing up stands. Files that are backed up have no state.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum FileState {
+    /// Waiting to be backed up
+    Pending,
+    /// Started by a run that never finished it, so its copy may be half-written
+    InProgress,
+    /// Backing it up failed on every attempt so far
+    Failed { error: String, attempts: u32 },
+    /// Backed up, but it kept changing while it was read, so its copy may be torn
+    Inconsistent,
+}
+
+/// A change to the registry, journaled as soon as it happens so it survives a crash before the
+/// registry itself is saved
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum JournalEntry {
+    /// A file was backed up
+    Stored {
+        path: PathBuf,
+        hash: String,
+        stamp: FileStamp,
+        #[serde(default)]
+        metadata: Option<FileMetadata>,
+    },
+    /// A tracked file disappeared from the source
+    Deleted { path: PathBuf, tombstone: Tombstone },
+    /// A worker started backing up a file
+    Started { path: PathBuf },
+    /// Backing up a file failed
+    Failed { path: PathBuf, error: String },
+    /// A file that was just stored changed while it was read
+    Inconsistent { path: PathBuf },
+}
+
+/// Path of the journal kept next to the registry at `registry_path`
+pub fn journal_path(registry_path: &Path) -> PathBuf {
+    let mut file_name = registry_path.file_name().map(OsString::from).unwrap_or_default();
+    file_name.push(".journal");
+    registry_path.with_file_name(file_name)
+}
+
+/// Append-only log of the changes made since the registry was last saved. Safe to share
+/// between worker threads.
+pub struct Journal {
+    file: Mutex<File>,
+    durability: Durability,
+}
+
+impl Journal {
+    /// Open the journal of the registry at `registry_path`, syncing each entry to disk as
+    /// `durability` asks
+    pub fn open(registry_path: &Path, durability: Durability) -> Result<Self> {
+        Ok(Self {
+            file: Mutex::new(compression::open_line_log(&journal_path(registry_path))?),
+            durability,
+        })
+    }
+
+    /// Write `entry` as a single line so a crash can only ever cut off the last entry
+    pub fn append(&self, entry: &JournalEntry) -> Result<()> {
+        let line = format!("{}\n", serde_json::to_string(entry)?);
+        let mut file = self.file.lock().unwrap();
+        file.write_all(line.as_bytes())?;
+        if self.durability != Durability::None {
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Run `f` while no entries can be appended, so the registry can be checkpointed and the
+    /// journal cleared without losing an entry written in between
+    pub fn pause<T>(&self, f: impl FnOnce() -> T) -> T {
+        let _file = self.file.lock().unwrap();
+        f()
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize, Default)]
 pub struct HashRegistry {
@@ -13,6 +132,23 @@ pub struct HashRegistry {
     pub hashes: Mutex<HashMap<PathBuf, String>>,
     #[serde(rename = "hashes")]
     serialized_hashes: HashMap<PathBuf, String>,
+    #[serde(skip)]
+    pub stamps: Mutex<HashMap<PathBuf, FileStamp>>,
+    // Registries written before stamps were tracked don't have this field
+    #[serde(rename = "stamps", default)]
+    serialized_stamps: HashMap<PathBuf, FileStamp>,
+    #[serde(skip)]
+    pub tombstones: Mutex<HashMap<PathBuf, Tombstone>>,
+    #[serde(rename = "tombstones", default)]
+    serialized_tombstones: HashMap<PathBuf, Tombstone>,
+    #[serde(skip)]
+    pub states: Mutex<HashMap<PathBuf, FileState>>,
+    #[serde(rename = "states", default)]
+    serialized_states: HashMap<PathBuf, FileState>,
+    #[serde(skip)]
+    pub metadata: Mutex<HashMap<PathBuf, FileMetadata>>,
+    #[serde(rename = "metadata", default)]
+    serialized_metadata: HashMap<PathBuf, FileMetadata>,
 }
 
 impl HashRegistry {
@@ -20,36 +156,119 @@ impl HashRegistry {
         Self {
             hashes: Mutex::new(HashMap::new()),
             serialized_hashes: HashMap::new(),
+            stamps: Mutex::new(HashMap::new()),
+            serialized_stamps: HashMap::new(),
+            tombstones: Mutex::new(HashMap::new()),
+            serialized_tombstones: HashMap::new(),
+            states: Mutex::new(HashMap::new()),
+            serialized_states: HashMap::new(),
+            metadata: Mutex::new(HashMap::new()),
+            serialized_metadata: HashMap::new(),
         }
     }
 
+    /// Load the registry and replay any changes journaled since it was last saved
     pub fn load_from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
-        match fs::read_to_string(path) {
+        let path = path.as_ref();
+        let mut registry = match fs::read_to_string(path) {
             Ok(content) => {
                 let registry: HashRegistry = serde_json::from_str(&content)?;
                 let hashes_map = registry.serialized_hashes.clone();
-                Ok(Self {
+                let stamps_map = registry.serialized_stamps.clone();
+                let tombstones_map = registry.serialized_tombstones.clone();
+                let states_map = registry.serialized_states.clone();
+                let metadata_map = registry.serialized_metadata.clone();
+                Self {
                     hashes: Mutex::new(hashes_map),
                     serialized_hashes: registry.serialized_hashes,
-                })
+                    stamps: Mutex::new(stamps_map),
+                    serialized_stamps: registry.serialized_stamps,
+                    tombstones: Mutex::new(tombstones_map),
+                    serialized_tombstones: registry.serialized_tombstones,
+                    states: Mutex::new(states_map),
+                    serialized_states: registry.serialized_states,
+                    metadata: Mutex::new(metadata_map),
+                    serialized_metadata: registry.serialized_metadata,
+                }
             }
             Err(_) => {
                 // Return empty registry if file doesn't exist
-                Ok(Self::new())
+                Self::new()
+            }
+        };
+
+        if let Ok(file) = File::open(journal_path(path)) {
+            for line in BufReader::new(file).lines() {
+                // Entries cut short by a crash are skipped
+                if let Ok(entry) = serde_json::from_str::<JournalEntry>(&line?) {
+                    registry.apply(entry);
+                }
             }
         }
+
+        Ok(registry)
+    }
+
+    fn apply(&mut self, entry: JournalEntry) {
+        match entry {
+            JournalEntry::Stored {
+                path,
+                hash,
+                stamp,
+                metadata,
+            } => {
+                self.set_stamp(path.clone(), stamp);
+                if let Some(metadata) = metadata {
+                    self.set_metadata(path.clone(), metadata);
+                }
+                self.set_hash(path, hash);
+            }
+            JournalEntry::Deleted { path, tombstone } => {
+                self.hashes.lock().unwrap().remove(&path);
+                self.stamps.lock().unwrap().remove(&path);
+                self.states.lock().unwrap().remove(&path);
+                self.metadata.lock().unwrap().remove(&path);
+                self.tombstones.lock().unwrap().insert(path, tombstone);
+            }
+            JournalEntry::Started { path } => self.mark_started(path),
+            JournalEntry::Failed { path, error } => self.record_failure(path, error),
+            JournalEntry::Inconsistent { path } => self.mark_inconsistent(path),
+        }
     }
 
     pub fn save_to_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
         // Update serialized_hashes with current state
         let hashes_guard = self.hashes.lock().unwrap();
+        let stamps_guard = self.stamps.lock().unwrap();
+        let tombstones_guard = self.tombstones.lock().unwrap();
+        let states_guard = self.states.lock().unwrap();
+        let metadata_guard = self.metadata.lock().unwrap();
         let serialized = Self {
             hashes: Mutex::new(HashMap::new()),
             serialized_hashes: hashes_guard.clone(),
+            stamps: Mutex::new(HashMap::new()),
+            serialized_stamps: stamps_guard.clone(),
+            tombstones: Mutex::new(HashMap::new()),
+            serialized_tombstones: tombstones_guard.clone(),
+            states: Mutex::new(HashMap::new()),
+            serialized_states: states_guard.clone(),
+            metadata: Mutex::new(HashMap::new()),
+            serialized_metadata: metadata_guard.clone(),
         };
         
         let content = serde_json::to_string(&serialized)?;
-        fs::write(path, content)?;
+        // The registry must be on disk before the journal that led up to it is cleared
+        compression::write_atomically(path.as_ref(), content.as_bytes(), Durability::File)?;
+        Ok(())
+    }
+
+    /// Save the registry and clear the journal, whose changes it now includes
+    pub fn checkpoint(&self, path: &Path) -> Result<()> {
+        self.save_to_file(path)?;
+        let journal_file = journal_path(path);
+        if journal_file.exists() {
+            OpenOptions::new().write(true).open(&journal_file)?.set_len(0)?;
+        }
         Ok(())
     }
 
@@ -63,19 +282,155 @@ impl HashRegistry {
         hashes_guard.get(path).cloned()
     }
 
-    pub fn set_hash(&mut self, path: PathBuf, hash: String) {
+    /// Records the hash of a backed up file, clearing any tombstone left by an earlier deletion
+    /// and marking it done
+    pub fn set_hash(&self, path: PathBuf, hash: String) {
+        let mut tombstones_guard = self.tombstones.lock().unwrap();
+        tombstones_guard.remove(&path);
+        let mut states_guard = self.states.lock().unwrap();
+        states_guard.remove(&path);
         let mut hashes_guard = self.hashes.lock().unwrap();
         hashes_guard.insert(path, hash);
     }
 
+    /// Hash of the most recent backup of `path`, even if the file has since been deleted
+    pub fn last_known_hash(&self, path: &Path) -> Option<String> {
+        self.get_hash(path).or_else(|| {
+            let tombstones_guard = self.tombstones.lock().unwrap();
+            tombstones_guard.get(path).map(|tombstone| tombstone.hash.clone())
+        })
+    }
+
+    /// Returns every path that currently has a hash
+    pub fn tracked_paths(&self) -> Vec<PathBuf> {
+        let hashes_guard = self.hashes.lock().unwrap();
+        hashes_guard.keys().cloned().collect()
+    }
+
+    /// Moves a tracked path from the live hashes to the tombstones
+    pub fn mark_deleted(&self, path: &Path) -> Option<Tombstone> {
+        let hash = self.hashes.lock().unwrap().remove(path)?;
+        self.stamps.lock().unwrap().remove(path);
+        self.states.lock().unwrap().remove(path);
+        self.metadata.lock().unwrap().remove(path);
+
+        let deleted_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let tombstone = Tombstone { deleted_at, hash };
+        let mut tombstones_guard = self.tombstones.lock().unwrap();
+        tombstones_guard.insert(path.to_path_buf(), tombstone.clone());
+        Some(tombstone)
+    }
+
+    pub fn get_state(&self, path: &Path) -> Option<FileState> {
+        let states_guard = self.states.lock().unwrap();
+        states_guard.get(path).cloned()
+    }
+
+    /// Mark `files` as the work of the coming run. Files that failed before keep their attempt
+    /// count, the others become pending, and files that no longer need backing up lose their
+    /// state.
+    pub fn plan(&self, files: &[PathBuf]) {
+        let mut states_guard = self.states.lock().unwrap();
+        let mut planned = HashMap::with_capacity(files.len());
+        for path in files {
+            let state = match states_guard.remove(path) {
+                Some(failed @ FileState::Failed { .. }) => failed,
+                _ => FileState::Pending,
+            };
+            planned.insert(path.clone(), state);
+        }
+        *states_guard = planned;
+    }
+
+    /// Records that a worker started backing up `path`, so a checkpoint taken while it's
+    /// being written keeps it in progress. A failed file keeps its attempt count until it
+    /// succeeds.
+    pub fn mark_started(&self, path: PathBuf) {
+        let mut states_guard = self.states.lock().unwrap();
+        let state = states_guard.entry(path).or_insert(FileState::InProgress);
+        if *state == FileState::Pending {
+            *state = FileState::InProgress;
+        }
+    }
+
+    /// Records a failed attempt at backing up `path`
+    pub fn record_failure(&self, path: PathBuf, error: String) {
+        let mut states_guard = self.states.lock().unwrap();
+        let attempts = match states_guard.get(&path) {
+            Some(FileState::Failed { attempts, .. }) => attempts + 1,
+            _ => 1,
+        };
+        states_guard.insert(path, FileState::Failed { error, attempts });
+    }
+
+    /// Records that the copy of `path` that was just stored may be torn, so it's backed up
+    /// again next run
+    pub fn mark_inconsistent(&self, path: PathBuf) {
+        let mut states_guard = self.states.lock().unwrap();
+        states_guard.insert(path, FileState::Inconsistent);
+    }
+
+    pub fn get_stamp(&self, path: &Path) -> Option<FileStamp> {
+        let stamps_guard = self.stamps.lock().unwrap();
+        stamps_guard.get(path).copied()
+    }
+
+    pub fn set_stamp(&self, path: PathBuf, stamp: FileStamp) {
+        let mut stamps_guard = self.stamps.lock().unwrap();
+        stamps_guard.insert(path, stamp);
+    }
+
+    pub fn get_metadata(&self, path: &Path) -> Option<FileMetadata> {
+        let metadata_guard = self.metadata.lock().unwrap();
+        metadata_guard.get(path).cloned()
+    }
+
+    /// Records the timestamps, permissions, ownership and extended attributes of a tracked file
+    pub fn set_metadata(&self, path: PathBuf, metadata: FileMetadata) {
+        let mut metadata_guard = self.metadata.lock().unwrap();
+        metadata_guard.insert(path, metadata);
+    }
+
     pub fn len(&self) -> usize {
         let hashes_guard = self.hashes.lock().unwrap();
         hashes_guard.len()
     }
 }
 
+/// Reader that hashes everything read through it, so one pass over a file can feed both the
+/// hasher and another consumer such as a compressor
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: Hasher,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Hasher::new(),
+        }
+    }
+
+    /// Hex encoded hash of the bytes read so far
+    pub fn hash(&self) -> String {
+        self.hasher.finalize().to_hex().to_string()
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        self.hasher.update(&buf[..count]);
+        Ok(count)
+    }
+}
+
 pub fn hash_file<P: AsRef<Path>>(path: P) -> Result<String> {
-    let file = File::open(path)?;
+    let file = throttle::open(path)?;
     let mut reader = BufReader::new(file);
     let mut hasher = Hasher::new();
 
@@ -95,6 +450,7 @@ pub fn hash_file<P: AsRef<Path>>(path: P) -> Result<String> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::io::Write;
     use tempfile::{NamedTempFile, tempdir};
 
     #[test]
@@ -105,7 +461,7 @@ mod tests {
 
     #[test]
     fn test_hash_registry_has_hash() {
-        let mut registry = HashRegistry::new();
+        let registry = HashRegistry::new();
         let path = PathBuf::from("/test/file.txt");
         let hash = "test_hash".to_string();
         
@@ -119,7 +475,7 @@ mod tests {
 
     #[test]
     fn test_hash_registry_get_hash() {
-        let mut registry = HashRegistry::new();
+        let registry = HashRegistry::new();
         let path = PathBuf::from("/test/file.txt");
         let hash = "test_hash".to_string();
         
@@ -133,7 +489,7 @@ mod tests {
 
     #[test]
     fn test_hash_registry_set_hash() {
-        let mut registry = HashRegistry::new();
+        let registry = HashRegistry::new();
         let path = PathBuf::from("/test/file.txt");
         let hash = "test_hash".to_string();
         
@@ -151,7 +507,7 @@ mod tests {
 
     #[test]
     fn test_hash_registry_len() {
-        let mut registry = HashRegistry::new();
+        let registry = HashRegistry::new();
         assert_eq!(registry.len(), 0);
         
         registry.set_hash(PathBuf::from("/test/file1.txt"), "hash1".to_string());
@@ -167,7 +523,7 @@ mod tests {
     #[test]
     fn test_hash_registry_save_and_load() {
         // Create a registry and add some hashes
-        let mut registry = HashRegistry::new();
+        let registry = HashRegistry::new();
         registry.set_hash(PathBuf::from("/test/file1.txt"), "hash1".to_string());
         registry.set_hash(PathBuf::from("/test/file2.txt"), "hash2".to_string());
         
@@ -191,6 +547,241 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_hash_registry_stamps() {
+        let registry = HashRegistry::new();
+        let path = PathBuf::from("/test/file.txt");
+        let stamp = FileStamp {
+            size: 42,
+            modified: Duration::new(1_700_000_000, 123),
+        };
+
+        // Initially should return None
+        assert_eq!(registry.get_stamp(&path), None);
+
+        // After setting, should return the stamp
+        registry.set_stamp(path.clone(), stamp);
+        assert_eq!(registry.get_stamp(&path), Some(stamp));
+
+        // Stamps survive a save and load round trip
+        let temp_dir = tempdir().unwrap();
+        let file_path = temp_dir.path().join("hashes.json");
+        registry.save_to_file(&file_path).unwrap();
+        let loaded_registry = HashRegistry::load_from_file(&file_path).unwrap();
+        assert_eq!(loaded_registry.get_stamp(&path), Some(stamp));
+    }
+
+    #[test]
+    fn test_hash_registry_mark_deleted() {
+        let registry = HashRegistry::new();
+        let path = PathBuf::from("/test/file.txt");
+
+        // Untracked paths can't be marked deleted
+        assert_eq!(registry.mark_deleted(&path), None);
+
+        // Marking a tracked path moves its hash into a tombstone
+        registry.set_hash(path.clone(), "hash1".to_string());
+        let tombstone = registry.mark_deleted(&path).unwrap();
+        assert_eq!(tombstone.hash, "hash1");
+        assert!(tombstone.deleted_at > 0);
+        assert!(!registry.has_hash(&path));
+        assert_eq!(registry.tombstones.lock().unwrap().get(&path), Some(&tombstone));
+        assert!(registry.tracked_paths().is_empty());
+
+        // Tombstones survive a save and load round trip
+        let temp_dir = tempdir().unwrap();
+        let file_path = temp_dir.path().join("hashes.json");
+        registry.save_to_file(&file_path).unwrap();
+        let loaded_registry = HashRegistry::load_from_file(&file_path).unwrap();
+        assert_eq!(loaded_registry.tombstones.lock().unwrap().get(&path), Some(&tombstone));
+
+        // The last hash is still known after deletion
+        assert_eq!(loaded_registry.last_known_hash(&path), Some("hash1".to_string()));
+
+        // A file that reappears clears its tombstone
+        loaded_registry.set_hash(path.clone(), "hash2".to_string());
+        assert!(loaded_registry.tombstones.lock().unwrap().is_empty());
+        assert_eq!(loaded_registry.tracked_paths(), vec![path]);
+    }
+
+    #[test]
+    fn test_hash_registry_replays_journal() {
+        let temp_dir = tempdir().unwrap();
+        let file_path = temp_dir.path().join("hashes.json");
+        let stamp = FileStamp {
+            size: 7,
+            modified: Duration::new(1_700_000_000, 0),
+        };
+
+        // A saved registry tracks two files
+        let registry = HashRegistry::new();
+        registry.set_hash(PathBuf::from("/test/kept.txt"), "hash1".to_string());
+        registry.set_hash(PathBuf::from("/test/gone.txt"), "hash2".to_string());
+        registry.checkpoint(&file_path).unwrap();
+
+        // Later changes only reach the journal before a crash, the last one cut short
+        let journal = Journal::open(&file_path, Durability::File).unwrap();
+        journal
+            .append(&JournalEntry::Stored {
+                path: PathBuf::from("/test/new.txt"),
+                hash: "hash3".to_string(),
+                stamp,
+                metadata: None,
+            })
+            .unwrap();
+        let tombstone = Tombstone {
+            deleted_at: 1_700_000_100,
+            hash: "hash2".to_string(),
+        };
+        journal
+            .append(&JournalEntry::Deleted {
+                path: PathBuf::from("/test/gone.txt"),
+                tombstone: tombstone.clone(),
+            })
+            .unwrap();
+        journal.file.lock().unwrap().write_all(br#"{"op":"stored","pa"#).unwrap();
+
+        // Loading replays the complete entries on top of the saved registry
+        let loaded = HashRegistry::load_from_file(&file_path).unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded.get_hash(Path::new("/test/new.txt")), Some("hash3".to_string()));
+        assert_eq!(loaded.get_stamp(Path::new("/test/new.txt")), Some(stamp));
+        assert_eq!(
+            loaded.tombstones.lock().unwrap().get(Path::new("/test/gone.txt")),
+            Some(&tombstone)
+        );
+
+        // A checkpoint folds the journal into the registry and clears it
+        loaded.checkpoint(&file_path).unwrap();
+        assert_eq!(fs::metadata(journal_path(&file_path)).unwrap().len(), 0);
+        let reloaded = HashRegistry::load_from_file(&file_path).unwrap();
+        assert_eq!(reloaded.get_hash(Path::new("/test/new.txt")), Some("hash3".to_string()));
+
+        // Entries appended after reopening the journal still replay
+        Journal::open(&file_path, Durability::File)
+            .unwrap()
+            .append(&JournalEntry::Stored {
+                path: PathBuf::from("/test/later.txt"),
+                hash: "hash4".to_string(),
+                stamp,
+                metadata: None,
+            })
+            .unwrap();
+        let reloaded = HashRegistry::load_from_file(&file_path).unwrap();
+        assert_eq!(reloaded.len(), 3);
+    }
+
+    #[test]
+    fn test_hash_registry_file_states() {
+        let temp_dir = tempdir().unwrap();
+        let file_path = temp_dir.path().join("hashes.json");
+        let done = PathBuf::from("/test/done.txt");
+        let started = PathBuf::from("/test/started.txt");
+        let failing = PathBuf::from("/test/failing.txt");
+        let torn = PathBuf::from("/test/torn.txt");
+        let untouched = PathBuf::from("/test/untouched.txt");
+
+        // Planning a run makes every file pending
+        let registry = HashRegistry::new();
+        registry.plan(&[done.clone(), started.clone(), failing.clone(), torn.clone(), untouched.clone()]);
+        assert_eq!(registry.get_state(&untouched), Some(FileState::Pending));
+        registry.checkpoint(&file_path).unwrap();
+
+        // The run gets through part of the work before it crashes
+        let journal = Journal::open(&file_path, Durability::File).unwrap();
+        let stamp = FileStamp {
+            size: 1,
+            modified: Duration::new(1_700_000_000, 0),
+        };
+        for entry in [
+            JournalEntry::Started { path: done.clone() },
+            JournalEntry::Stored { path: done.clone(), hash: "hash1".to_string(), stamp, metadata: None },
+            JournalEntry::Started { path: started.clone() },
+            JournalEntry::Started { path: failing.clone() },
+            JournalEntry::Failed { path: failing.clone(), error: "denied".to_string() },
+            JournalEntry::Started { path: torn.clone() },
+            JournalEntry::Stored { path: torn.clone(), hash: "hash3".to_string(), stamp, metadata: None },
+            JournalEntry::Inconsistent { path: torn.clone() },
+        ] {
+            journal.append(&entry).unwrap();
+        }
+
+        // Replaying tells every kind of unfinished file apart
+        let loaded = HashRegistry::load_from_file(&file_path).unwrap();
+        assert_eq!(loaded.get_state(&done), None);
+        assert_eq!(loaded.get_state(&started), Some(FileState::InProgress));
+        assert_eq!(loaded.get_state(&untouched), Some(FileState::Pending));
+        assert_eq!(loaded.get_state(&torn), Some(FileState::Inconsistent));
+        assert!(loaded.has_hash(&torn));
+        assert_eq!(
+            loaded.get_state(&failing),
+            Some(FileState::Failed { error: "denied".to_string(), attempts: 1 })
+        );
+
+        // Failures count up across runs and survive planning the next one
+        loaded.plan(&[started.clone(), failing.clone()]);
+        loaded.record_failure(failing.clone(), "still denied".to_string());
+        assert_eq!(loaded.get_state(&started), Some(FileState::Pending));
+        assert_eq!(loaded.get_state(&untouched), None);
+        assert_eq!(
+            loaded.get_state(&failing),
+            Some(FileState::Failed { error: "still denied".to_string(), attempts: 2 })
+        );
+
+        // A file that finally succeeds is done
+        loaded.set_hash(failing.clone(), "hash2".to_string());
+        assert_eq!(loaded.get_state(&failing), None);
+    }
+
+    #[test]
+    fn test_hash_registry_checkpoints_files_in_progress() {
+        let temp_dir = tempdir().unwrap();
+        let file_path = temp_dir.path().join("hashes.json");
+        let in_flight = PathBuf::from("/test/in_flight.txt");
+        let waiting = PathBuf::from("/test/waiting.txt");
+
+        let registry = HashRegistry::new();
+        registry.plan(&[in_flight.clone(), waiting.clone()]);
+        registry.checkpoint(&file_path).unwrap();
+
+        // A worker starts a file, then the registry is checkpointed while it's being written,
+        // which clears the journal
+        let journal = Journal::open(&file_path, Durability::File).unwrap();
+        registry.mark_started(in_flight.clone());
+        journal.append(&JournalEntry::Started { path: in_flight.clone() }).unwrap();
+        journal.pause(|| registry.checkpoint(&file_path)).unwrap();
+
+        // After a crash the file is still known to have been left mid-write
+        let loaded = HashRegistry::load_from_file(&file_path).unwrap();
+        assert_eq!(loaded.get_state(&in_flight), Some(FileState::InProgress));
+        assert_eq!(loaded.get_state(&waiting), Some(FileState::Pending));
+    }
+
+    #[test]
+    fn test_hash_registry_load_without_stamps() {
+        // Registries saved before stamps were tracked only contain hashes
+        let temp_dir = tempdir().unwrap();
+        let file_path = temp_dir.path().join("hashes.json");
+        fs::write(&file_path, r#"{"hashes":{"/test/file1.txt":"hash1"}}"#).unwrap();
+
+        let registry = HashRegistry::load_from_file(&file_path).unwrap();
+        let path = PathBuf::from("/test/file1.txt");
+        assert_eq!(registry.get_hash(&path), Some("hash1".to_string()));
+        assert_eq!(registry.get_stamp(&path), None);
+    }
+
+    #[test]
+    fn test_file_stamp_from_path() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        temp_file.write_all(b"stamp me").unwrap();
+        temp_file.flush().unwrap();
+
+        let stamp = FileStamp::from_path(temp_file.path()).unwrap();
+        let modified = fs::metadata(temp_file.path()).unwrap().modified().unwrap();
+        assert_eq!(stamp.size, 8);
+        assert_eq!(stamp.modified, modified.duration_since(UNIX_EPOCH).unwrap());
+    }
+
     #[test]
     fn test_hash_registry_load_nonexistent_file() {
         // Load from a non-existent file
@@ -240,6 +831,20 @@ mod tests {
         assert_eq!(hash, expected_hash);
     }
 
+    #[test]
+    fn test_hashing_reader() {
+        let content = b"Content read through the hashing reader".repeat(1000);
+        let mut reader = HashingReader::new(&content[..]);
+
+        // The bytes pass through unchanged
+        let mut read_back = Vec::new();
+        reader.read_to_end(&mut read_back).unwrap();
+        assert_eq!(read_back, content);
+
+        // The hash covers everything that was read
+        assert_eq!(reader.hash(), blake3::hash(&content).to_hex().to_string());
+    }
+
     #[test]
     fn test_hash_file_nonexistent() {
         // Try to hash a non-existent file
@@ -2,11 +2,29 @@ mod backup;
 mod compression;
 mod config;
 mod hashing;
+mod metadata;
+mod pack;
+mod progress;
+mod report;
+mod restore;
+mod snapshot;
+mod store;
+mod throttle;
 
-use anyhow::{Context, Result};
-use clap::{Parser, Subcommand};
+use anyhow::{bail, Context, Result};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use cliclack::{confirm, intro, log, outro, select, input};
+use indicatif::HumanBytes;
+use std::collections::BTreeMap;
 use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Exit code of a backup that stopped early because of Ctrl-C or SIGTERM
+const EXIT_INTERRUPTED: i32 = 130;
+/// Exit code of a backup that finished, but couldn't back up some files
+const EXIT_FILES_FAILED: i32 = 2;
 
 #[derive(Parser)]
 #[clap(version, about, long_about = None)]
@@ -15,6 +33,52 @@ struct Cli {
     command: Option<Commands>,
 }
 
+/// How the output of a command is printed
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+/// Which jobs of the configuration file a command works on
+#[derive(Args)]
+struct JobArgs {
+    /// Name of a job defined under [jobs] in the configuration file
+    job: Option<String>,
+
+    /// Work on every job defined in the configuration file, one after another
+    #[clap(long, conflicts_with = "job")]
+    all: bool,
+}
+
+/// Settings that override the configuration to keep a backup from hogging the machine
+#[derive(Args)]
+struct ResourceArgs {
+    /// Number of files to process at once (defaults to one per CPU core)
+    #[clap(long)]
+    workers: Option<usize>,
+
+    /// Cap on how many bytes per second are read, and separately written
+    #[clap(long)]
+    max_bytes_per_second: Option<u64>,
+
+    /// Lower the CPU and I/O scheduling priority of the backup (Linux only)
+    #[clap(long)]
+    low_priority: bool,
+}
+
+impl ResourceArgs {
+    fn apply(&self, config: &mut config::Config) {
+        if self.workers.is_some() {
+            config.workers = self.workers;
+        }
+        if self.max_bytes_per_second.is_some() {
+            config.max_bytes_per_second = self.max_bytes_per_second;
+        }
+        config.low_priority |= self.low_priority;
+    }
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Run backup with previously saved configuration
@@ -22,6 +86,24 @@ enum Commands {
         /// Path to the configuration file
         #[clap(short, long)]
         config: Option<PathBuf>,
+
+        #[clap(flatten)]
+        jobs: JobArgs,
+
+        /// Re-hash every tracked file instead of trusting unchanged size and mtime
+        #[clap(long)]
+        paranoid: bool,
+
+        /// Report what the backup would do without writing anything
+        #[clap(long)]
+        dry_run: bool,
+
+        /// Output format of the dry run report
+        #[clap(long, value_enum, default_value = "text", requires = "dry_run")]
+        format: OutputFormat,
+
+        #[clap(flatten)]
+        resources: ResourceArgs,
     },
     /// Set up a new backup configuration
     Setup {
@@ -34,6 +116,65 @@ enum Commands {
         /// Path to the configuration file
         #[clap(short, long)]
         config: Option<PathBuf>,
+
+        #[clap(flatten)]
+        jobs: JobArgs,
+
+        /// Re-hash every tracked file instead of trusting unchanged size and mtime
+        #[clap(long)]
+        paranoid: bool,
+
+        #[clap(flatten)]
+        resources: ResourceArgs,
+    },
+    /// List the snapshots stored in the backup destination
+    Snapshots {
+        /// Path to the configuration file
+        #[clap(short, long)]
+        config: Option<PathBuf>,
+
+        /// Name of the job whose snapshots to list
+        job: Option<String>,
+    },
+    /// Restore the files of a snapshot
+    Restore {
+        /// Path to the configuration file
+        #[clap(short, long)]
+        config: Option<PathBuf>,
+
+        /// Name of the job whose snapshot to restore
+        job: Option<String>,
+
+        /// Id of the snapshot to restore (defaults to the latest)
+        #[clap(long)]
+        snapshot: Option<String>,
+
+        /// Directory to restore the files into
+        #[clap(short, long)]
+        target: PathBuf,
+
+        /// Leave restored files owned by the current user instead of their original owner and
+        /// group, e.g. when restoring as a non-root user
+        #[clap(long)]
+        no_owner: bool,
+    },
+    /// Check that every file in a snapshot can be read back and matches its hash
+    Verify {
+        /// Path to the configuration file
+        #[clap(short, long)]
+        config: Option<PathBuf>,
+
+        /// Name of the job whose snapshot to verify
+        job: Option<String>,
+
+        /// Id of the snapshot to verify (defaults to the latest)
+        #[clap(long)]
+        snapshot: Option<String>,
+    },
+    /// Work with the named jobs of a configuration file
+    Jobs {
+        #[clap(subcommand)]
+        command: JobsCommands,
     },
     /// Decompress a file
     Decompress {
@@ -47,6 +188,16 @@ enum Commands {
     },
 }
 
+#[derive(Subcommand)]
+enum JobsCommands {
+    /// List the jobs defined in the configuration file
+    List {
+        /// Path to the configuration file
+        #[clap(short, long)]
+        config: Option<PathBuf>,
+    },
+}
+
 fn run_interactive_setup() -> Result<config::Config> {
     intro("MBBUT - Mackenzie Bowes' Back Up Tool")?;
 
@@ -91,10 +242,12 @@ fn run_interactive_setup() -> Result<config::Config> {
         .interact()?;
 
     // Create config with default blacklists
-    let mut config = config::Config::default();
-    config.source_path = Some(PathBuf::from(source_path));
-    config.destination_path = Some(PathBuf::from(destination_path));
-    config.hash_file_path = Some(PathBuf::from(hash_file_path));
+    let mut config = config::Config {
+        source_path: Some(PathBuf::from(source_path)),
+        destination_path: Some(PathBuf::from(destination_path)),
+        hash_file_path: Some(PathBuf::from(hash_file_path)),
+        ..config::Config::default()
+    };
 
     // Ask if user wants to customize blacklists
     let customize_blacklists =
@@ -118,45 +271,221 @@ fn run_interactive_setup() -> Result<config::Config> {
     Ok(config)
 }
 
+/// Load the configuration file at `path`, or `mbbut_config.toml` in the current directory
+fn load_config(path: Option<PathBuf>) -> Result<config::Config> {
+    let config_path = path.unwrap_or_else(|| PathBuf::from("mbbut_config.toml"));
+    config::Config::load_from_file(&config_path).context("Failed to load configuration file")
+}
+
+/// Settings of the job called `name`, or of the whole file if no job is named
+fn select_job(config: config::Config, name: Option<&str>) -> Result<config::Config> {
+    match name {
+        Some(name) => config.job(name),
+        None if !config.jobs.is_empty() && config.source_path.is_none() && config.sources.is_empty() => {
+            let names: Vec<&str> = config.jobs.keys().map(String::as_str).collect();
+            bail!("The configuration defines jobs, name one of {} or pass --all", names.join(", "))
+        }
+        None => Ok(config),
+    }
+}
+
+/// The jobs picked by `args`, paired with their names
+fn select_jobs(config: config::Config, args: &JobArgs) -> Result<Vec<(Option<String>, config::Config)>> {
+    if args.all {
+        let jobs = config.all_jobs()?;
+        if jobs.is_empty() {
+            bail!("The configuration defines no jobs");
+        }
+        Ok(jobs.into_iter().map(|(name, job)| (Some(name), job)).collect())
+    } else {
+        Ok(vec![(args.job.clone(), select_job(config, args.job.as_deref())?)])
+    }
+}
+
+/// Create a backup job for `config`, with the hash registry it points at
+fn load_backup_job(config: config::Config) -> Result<backup::BackupJob> {
+    let hash_file_path = config
+        .hash_file_path
+        .as_ref()
+        .context("Hash file path not set in config")?;
+    let hash_registry = hashing::HashRegistry::load_from_file(hash_file_path)
+        .context("Failed to load hash registry")?;
+    Ok(backup::BackupJob::new(config, hash_registry))
+}
+
+/// Run or resume each of `jobs` in turn, stopping them in an orderly way on Ctrl-C or SIGTERM.
+/// A job that stopped early exits the process with `EXIT_INTERRUPTED` once its progress is
+/// saved, without starting the jobs after it. If files failed in any job, the process exits
+/// with `EXIT_FILES_FAILED` once every job has run.
+fn run_backup_jobs(jobs: Vec<(Option<String>, config::Config)>, paranoid: bool, resume: bool) -> Result<()> {
+    let stop = Arc::new(AtomicBool::new(false));
+    let handler_stop = stop.clone();
+    ctrlc::set_handler(move || {
+        if handler_stop.swap(true, Ordering::SeqCst) {
+            // Give up on the files in progress, the next run cleans up after them
+            process::exit(EXIT_INTERRUPTED);
+        }
+        eprintln!("Stopping once the files in progress are finished. Press Ctrl-C again to stop now.");
+    })
+    .context("Failed to install the interrupt handler")?;
+
+    let job_count = jobs.len();
+    let mut files_failed = false;
+    let mut failed_jobs = Vec::new();
+    for (name, config) in jobs {
+        let name = name.unwrap_or_default();
+        if job_count > 1 {
+            println!("Running job {}", name);
+        }
+
+        let result = load_backup_job(config).and_then(|mut backup_job| {
+            backup_job.paranoid = paranoid;
+            backup_job.stop = stop.clone();
+            if resume {
+                backup_job.resume()
+            } else {
+                backup_job.run()
+            }
+        });
+
+        match result {
+            Ok(()) => {}
+            Err(e) if e.is::<backup::Interrupted>() => {
+                eprintln!("Backup interrupted. Run `mbbut resume` with the same arguments to continue where it left off.");
+                process::exit(EXIT_INTERRUPTED);
+            }
+            Err(e) if e.is::<backup::FilesFailed>() => {
+                eprintln!("{}", e);
+                files_failed = true;
+            }
+            Err(e) if job_count == 1 => return Err(e),
+            Err(e) => {
+                eprintln!("Job {} failed: {:#}", name, e);
+                failed_jobs.push(name);
+            }
+        }
+    }
+
+    if !failed_jobs.is_empty() {
+        bail!("{} of {} jobs failed: {}", failed_jobs.len(), job_count, failed_jobs.join(", "));
+    }
+    if files_failed {
+        process::exit(EXIT_FILES_FAILED);
+    }
+    Ok(())
+}
+
+/// Ask which job to work on if the configuration defines jobs and no source of its own
+fn choose_job(config: config::Config) -> Result<config::Config> {
+    if config.jobs.is_empty() || config.source_path.is_some() || !config.sources.is_empty() {
+        return Ok(config);
+    }
+
+    let mut prompt = select("Which job?");
+    for name in config.jobs.keys() {
+        prompt = prompt.item(name.clone(), name, "");
+    }
+    let name = prompt.interact()?;
+    config.job(&name)
+}
+
+/// Print the files a dry run found in each category, followed by the totals
+fn print_dry_run(report: &report::DryRunReport) {
+    for (label, files) in [
+        ("New", &report.new_files),
+        ("Changed", &report.changed_files),
+        ("Retried", &report.retried_files),
+        ("Unchanged", &report.unchanged_files),
+        ("Excluded", &report.excluded_files),
+        ("Deleted", &report.deleted_files),
+    ] {
+        println!("{} files: {}", label, files.len());
+        for path in files {
+            println!("  {}", path.display());
+        }
+    }
+
+    if !report.failures.is_empty() {
+        println!("Failures: {}", report.failures.len());
+        for failure in &report.failures {
+            println!(
+                "  {} ({}, {}): {}",
+                failure.path.display(),
+                failure.operation.name(),
+                failure.kind,
+                failure.error
+            );
+        }
+    }
+
+    println!(
+        "Would back up {} ({} estimated after compression)",
+        HumanBytes(report.total_bytes),
+        HumanBytes(report.estimated_compressed_bytes)
+    );
+}
+
 fn main() -> Result<()> {
     let cli = Cli::parse();
 
     match cli.command {
-        Some(Commands::Run { config }) => {
-            // Load config
-            let config_path = config.unwrap_or_else(|| PathBuf::from("mbbut_config.toml"));
-            let config = config::Config::load_from_file(&config_path)
-                .context("Failed to load configuration file")?;
-
-            // Load hash registry
-            let hash_file_path = config
-                .hash_file_path
-                .as_ref()
-                .context("Hash file path not set in config")?;
-            let hash_registry = hashing::HashRegistry::load_from_file(hash_file_path)
-                .context("Failed to load hash registry")?;
+        Some(Commands::Run {
+            config,
+            jobs,
+            paranoid,
+            dry_run,
+            format,
+            resources,
+        }) => {
+            let mut jobs = select_jobs(load_config(config)?, &jobs)?;
+            for (_, config) in &mut jobs {
+                resources.apply(config);
+            }
 
-            // Create and run backup job
-            let mut backup_job = backup::BackupJob::new(config, hash_registry);
-            backup_job.run()?;
+            if dry_run {
+                let mut reports = BTreeMap::new();
+                for (name, config) in jobs {
+                    let mut backup_job = load_backup_job(config)?;
+                    backup_job.paranoid = paranoid;
+                    reports.insert(name, backup_job.dry_run()?);
+                }
+                match format {
+                    OutputFormat::Text => {
+                        for (name, report) in &reports {
+                            if let Some(name) = name.as_ref().filter(|_| reports.len() > 1) {
+                                println!("Job {}:", name);
+                            }
+                            print_dry_run(report);
+                        }
+                    }
+                    // A single report as before, or one per job when running them all
+                    OutputFormat::Json if reports.len() == 1 => {
+                        let report = reports.values().next().unwrap();
+                        println!("{}", serde_json::to_string_pretty(report)?);
+                    }
+                    OutputFormat::Json => {
+                        let reports: BTreeMap<String, _> = reports
+                            .into_iter()
+                            .map(|(name, report)| (name.unwrap_or_default(), report))
+                            .collect();
+                        println!("{}", serde_json::to_string_pretty(&reports)?);
+                    }
+                }
+            } else {
+                run_backup_jobs(jobs, paranoid, false)?;
+            }
         }
-        Some(Commands::Resume { config }) => {
-            // Load config
-            let config_path = config.unwrap_or_else(|| PathBuf::from("mbbut_config.toml"));
-            let config = config::Config::load_from_file(&config_path)
-                .context("Failed to load configuration file")?;
-
-            // Load hash registry
-            let hash_file_path = config
-                .hash_file_path
-                .as_ref()
-                .context("Hash file path not set in config")?;
-            let hash_registry = hashing::HashRegistry::load_from_file(hash_file_path)
-                .context("Failed to load hash registry")?;
-
-            // Create and resume backup job
-            let mut backup_job = backup::BackupJob::new(config, hash_registry);
-            backup_job.resume()?;
+        Some(Commands::Resume {
+            config,
+            jobs,
+            paranoid,
+            resources,
+        }) => {
+            let mut jobs = select_jobs(load_config(config)?, &jobs)?;
+            for (_, config) in &mut jobs {
+                resources.apply(config);
+            }
+            run_backup_jobs(jobs, paranoid, true)?;
         }
         Some(Commands::Setup { output }) => {
             // Interactive setup
@@ -166,6 +495,97 @@ fn main() -> Result<()> {
             let output_path = output.unwrap_or_else(|| PathBuf::from("mbbut_config.toml"));
             config.save_to_file(output_path)?;
         }
+        Some(Commands::Snapshots { config, job }) => {
+            let config = select_job(load_config(config)?, job.as_deref())?;
+            let destination_path = config
+                .destination_path
+                .as_ref()
+                .context("Destination path not set in config")?;
+
+            let snapshots = snapshot::list_snapshots(destination_path)?;
+            if snapshots.is_empty() {
+                println!("No snapshots found in {}", destination_path.display());
+            }
+            for snapshot in snapshots {
+                println!(
+                    "{}  {}  {} files  {}",
+                    snapshot.id,
+                    snapshot.created_at_display(),
+                    snapshot.files.len(),
+                    HumanBytes(snapshot.total_size())
+                );
+            }
+        }
+        Some(Commands::Restore {
+            config,
+            job,
+            snapshot,
+            target,
+            no_owner,
+        }) => {
+            let config = select_job(load_config(config)?, job.as_deref())?;
+            let destination_path = config
+                .destination_path
+                .as_ref()
+                .context("Destination path not set in config")?;
+
+            let snapshot = snapshot::find_snapshot(destination_path, snapshot.as_deref())?;
+            log::info(format!("Restoring snapshot {}...", snapshot.id))?;
+
+            let restored = restore::restore_snapshot(&snapshot, destination_path, &target, !no_owner)
+                .context("Failed to restore snapshot")?;
+
+            log::success(format!("Restored {} files to {}", restored, target.display()))?;
+        }
+        Some(Commands::Verify { config, job, snapshot }) => {
+            let config = select_job(load_config(config)?, job.as_deref())?;
+            let destination_path = config
+                .destination_path
+                .as_ref()
+                .context("Destination path not set in config")?;
+
+            let snapshot = snapshot::find_snapshot(destination_path, snapshot.as_deref())?;
+            log::info(format!("Verifying snapshot {}...", snapshot.id))?;
+
+            let report = restore::verify_snapshot(&snapshot, destination_path)?;
+            for (path, reason) in &report.failures {
+                log::error(format!("{}: {}", path.display(), reason))?;
+            }
+            if !report.failures.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "{} of {} files failed verification",
+                    report.failures.len(),
+                    snapshot.files.len()
+                ));
+            }
+
+            log::success(format!("Verified {} files", report.verified))?;
+        }
+        Some(Commands::Jobs {
+            command: JobsCommands::List { config },
+        }) => {
+            let config = load_config(config)?;
+            let jobs = config.all_jobs()?;
+            if jobs.is_empty() {
+                println!("No jobs defined in the configuration file");
+            }
+            for (name, job) in jobs {
+                println!("{}", name);
+                for source in job.sources()? {
+                    if source.prefix.as_os_str().is_empty() {
+                        println!("  {}", source.path.display());
+                    } else {
+                        println!("  {} -> {}", source.path.display(), source.prefix.display());
+                    }
+                }
+                if let Some(destination_path) = &job.destination_path {
+                    println!("  destination: {}", destination_path.display());
+                }
+                if let Some(hash_file_path) = &job.hash_file_path {
+                    println!("  registry: {}", hash_file_path.display());
+                }
+            }
+        }
         Some(Commands::Decompress { source, destination }) => {
             log::info("Decompressing file...")?;
             
@@ -176,7 +596,7 @@ fn main() -> Result<()> {
             compression::decompress_file(&source, &destination)
                 .context("Failed to decompress file")?;
             
-            log::success(&format!("File decompressed to {}", destination.display()))?;
+            log::success(format!("File decompressed to {}", destination.display()))?;
         }
         None => {
             // If no command is provided, run interactive mode
@@ -200,18 +620,8 @@ fn main() -> Result<()> {
                         return Ok(());
                     }
 
-                    let config = config::Config::load_from_file(&config_path)
-                        .context("Failed to load configuration file")?;
-
-                    let hash_file_path = config
-                        .hash_file_path
-                        .as_ref()
-                        .context("Hash file path not set in config")?;
-                    let hash_registry = hashing::HashRegistry::load_from_file(hash_file_path)
-                        .context("Failed to load hash registry")?;
-
-                    let mut backup_job = backup::BackupJob::new(config, hash_registry);
-                    backup_job.run()?;
+                    let config = choose_job(load_config(Some(config_path))?)?;
+                    run_backup_jobs(vec![(None, config)], false, false)?;
                 }
                 "resume" => {
                     let config_path = PathBuf::from("mbbut_config.toml");
@@ -220,18 +630,8 @@ fn main() -> Result<()> {
                         return Ok(());
                     }
 
-                    let config = config::Config::load_from_file(&config_path)
-                        .context("Failed to load configuration file")?;
-
-                    let hash_file_path = config
-                        .hash_file_path
-                        .as_ref()
-                        .context("Hash file path not set in config")?;
-                    let hash_registry = hashing::HashRegistry::load_from_file(hash_file_path)
-                        .context("Failed to load hash registry")?;
-
-                    let mut backup_job = backup::BackupJob::new(config, hash_registry);
-                    backup_job.resume()?;
+                    let config = choose_job(load_config(Some(config_path))?)?;
+                    run_backup_jobs(vec![(None, config)], false, true)?;
                 }
                 "decompress" => {
                     let source_path: String = input("Path to compressed file")
@@ -268,7 +668,7 @@ fn main() -> Result<()> {
                     compression::decompress_file(&source, &destination)
                         .context("Failed to decompress file")?;
                         
-                    log::success(&format!("File decompressed to {}", destination.display()))?;
+                    log::success(format!("File decompressed to {}", destination.display()))?;
                 }
                 _ => unreachable!(),
             }
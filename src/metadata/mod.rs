@@ -0,0 +1,262 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, FileTimes, OpenOptions};
+use std::io;
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+/// Timestamps, permissions, ownership and extended attributes of a file, as far as the
+/// platform has them
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileMetadata {
+    /// Modification time since the Unix epoch, to the nanosecond
+    pub modified: Duration,
+    /// Access time since the Unix epoch, to the nanosecond, if the file system keeps it
+    #[serde(default)]
+    pub accessed: Option<Duration>,
+    /// Unix permission bits, including the setuid, setgid and sticky bits
+    #[serde(default)]
+    pub mode: Option<u32>,
+    #[serde(default)]
+    pub uid: Option<u32>,
+    #[serde(default)]
+    pub gid: Option<u32>,
+    /// Extended attributes by name. Attributes whose names aren't UTF-8 are left out.
+    #[serde(default)]
+    pub xattrs: BTreeMap<String, Vec<u8>>,
+}
+
+impl FileMetadata {
+    /// Capture the metadata of the file at `path`, given what `fs::metadata` returned for it.
+    /// Extended attributes that can't be read, e.g. `security.*` ones without the privilege
+    /// to, are left out and described in the returned problems instead of failing the capture.
+    pub fn capture(path: &Path, metadata: &fs::Metadata) -> Result<(Self, Vec<String>)> {
+        let since_epoch = |time: std::time::SystemTime| time.duration_since(UNIX_EPOCH).unwrap_or_default();
+
+        #[cfg(unix)]
+        let (mode, uid, gid) = {
+            use std::os::unix::fs::MetadataExt;
+            (Some(metadata.mode() & 0o7777), Some(metadata.uid()), Some(metadata.gid()))
+        };
+        #[cfg(not(unix))]
+        let (mode, uid, gid) = (None, None, None);
+
+        let (xattrs, problems) = read_xattrs(path);
+        let captured = Self {
+            modified: since_epoch(metadata.modified()?),
+            accessed: metadata.accessed().ok().map(since_epoch),
+            mode,
+            uid,
+            gid,
+            xattrs,
+        };
+        Ok((captured, problems))
+    }
+
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<(Self, Vec<String>)> {
+        let path = path.as_ref();
+        Self::capture(path, &fs::metadata(path)?)
+    }
+
+    /// Apply the metadata to the file at `path`. Its owner and group are only changed if
+    /// `restore_owner` is set, which usually takes root.
+    pub fn apply(&self, path: &Path, restore_owner: bool) -> Result<()> {
+        // Timestamps first, while the file is still writable
+        let mut times = FileTimes::new().set_modified(UNIX_EPOCH + self.modified);
+        if let Some(accessed) = self.accessed {
+            times = times.set_accessed(UNIX_EPOCH + accessed);
+        }
+        OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .set_times(times)
+            .context("Failed to set timestamps")?;
+
+        write_xattrs(path, &self.xattrs).context("Failed to set extended attributes")?;
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            // Changing the owner clears the setuid and setgid bits, so it comes before the mode
+            if restore_owner && (self.uid.is_some() || self.gid.is_some()) {
+                std::os::unix::fs::chown(path, self.uid, self.gid)
+                    .context("Failed to set owner, use --no-owner to restore files as the current user")?;
+            }
+            if let Some(mode) = self.mode {
+                fs::set_permissions(path, fs::Permissions::from_mode(mode)).context("Failed to set permissions")?;
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = restore_owner;
+
+        Ok(())
+    }
+}
+
+/// Whether an extended attribute call failed because the file system doesn't support them
+#[cfg(target_os = "linux")]
+fn is_unsupported(error: &io::Error) -> bool {
+    error.raw_os_error() == Some(libc::ENOTSUP)
+}
+
+/// Call `call` with a buffer of the size it asks for when passed an empty one, for the
+/// extended attribute calls that work that way
+#[cfg(target_os = "linux")]
+fn read_sized(call: impl Fn(*mut libc::c_void, usize) -> libc::ssize_t) -> io::Result<Vec<u8>> {
+    let size = call(std::ptr::null_mut(), 0);
+    if size < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut buffer = vec![0u8; size as usize];
+    let size = call(buffer.as_mut_ptr().cast(), buffer.len());
+    if size < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    buffer.truncate(size as usize);
+    Ok(buffer)
+}
+
+/// Read the extended attributes of the file at `path`, along with a description of each one
+/// that couldn't be read
+#[cfg(target_os = "linux")]
+fn read_xattrs(path: &Path) -> (BTreeMap<String, Vec<u8>>, Vec<String>) {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let mut xattrs = BTreeMap::new();
+    let mut problems = Vec::new();
+    let Ok(c_path) = CString::new(path.as_os_str().as_bytes()) else {
+        problems.push("Failed to list extended attributes: path contains a nul byte".to_string());
+        return (xattrs, problems);
+    };
+    let names = match read_sized(|buffer, size| unsafe { libc::listxattr(c_path.as_ptr(), buffer.cast(), size) }) {
+        Ok(names) => names,
+        Err(e) if is_unsupported(&e) => return (xattrs, problems),
+        Err(e) => {
+            problems.push(format!("Failed to list extended attributes: {}", e));
+            return (xattrs, problems);
+        }
+    };
+
+    for name in names.split(|&byte| byte == 0).filter(|name| !name.is_empty()) {
+        let Ok(name_str) = std::str::from_utf8(name) else {
+            continue;
+        };
+        let Ok(c_name) = CString::new(name) else {
+            continue;
+        };
+        match read_sized(|buffer, size| unsafe { libc::getxattr(c_path.as_ptr(), c_name.as_ptr(), buffer, size) }) {
+            Ok(value) => {
+                xattrs.insert(name_str.to_string(), value);
+            }
+            // Removed since it was listed
+            Err(e) if e.raw_os_error() == Some(libc::ENODATA) => {}
+            Err(e) => problems.push(format!("Failed to read extended attribute {}: {}", name_str, e)),
+        }
+    }
+    (xattrs, problems)
+}
+
+/// Extended attributes are only captured on Linux
+#[cfg(not(target_os = "linux"))]
+fn read_xattrs(_path: &Path) -> (BTreeMap<String, Vec<u8>>, Vec<String>) {
+    (BTreeMap::new(), Vec::new())
+}
+
+/// Set `xattrs` on the file at `path`, skipping them if its file system doesn't support them
+#[cfg(target_os = "linux")]
+fn write_xattrs(path: &Path, xattrs: &BTreeMap<String, Vec<u8>>) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    if xattrs.is_empty() {
+        return Ok(());
+    }
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    for (name, value) in xattrs {
+        let c_name = CString::new(name.as_str())?;
+        let result = unsafe {
+            libc::setxattr(c_path.as_ptr(), c_name.as_ptr(), value.as_ptr().cast(), value.len(), 0)
+        };
+        if result != 0 {
+            let error = io::Error::last_os_error();
+            if is_unsupported(&error) {
+                return Ok(());
+            }
+            return Err(io::Error::new(error.kind(), format!("{}: {}", name, error)));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn write_xattrs(_path: &Path, _xattrs: &BTreeMap<String, Vec<u8>>) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_capture_and_apply_metadata() {
+        let temp_dir = tempdir().unwrap();
+        let original = temp_dir.path().join("original.sh");
+        fs::write(&original, b"#!/bin/sh").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&original, fs::Permissions::from_mode(0o750)).unwrap();
+        }
+        let times = FileTimes::new()
+            .set_modified(UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789))
+            .set_accessed(UNIX_EPOCH + Duration::new(1_700_000_100, 987_654_321));
+        OpenOptions::new().write(true).open(&original).unwrap().set_times(times).unwrap();
+
+        let (metadata, problems) = FileMetadata::from_path(&original).unwrap();
+        assert!(problems.is_empty());
+        assert_eq!(metadata.modified, Duration::new(1_700_000_000, 123_456_789));
+        #[cfg(unix)]
+        assert_eq!(metadata.mode, Some(0o750));
+
+        // A restored copy gets the same timestamps, to the nanosecond, and permissions
+        let copy = temp_dir.path().join("copy.sh");
+        fs::write(&copy, b"#!/bin/sh").unwrap();
+        metadata.apply(&copy, false).unwrap();
+        let (copied, _) = FileMetadata::from_path(&copy).unwrap();
+        assert_eq!(copied.modified, metadata.modified);
+        assert_eq!(copied.accessed, metadata.accessed);
+        assert_eq!(copied.mode, metadata.mode);
+
+        // Metadata recorded before it was captured in full still loads
+        let old: FileMetadata = serde_json::from_str(r#"{"modified": {"secs": 1, "nanos": 2}}"#).unwrap();
+        assert_eq!(old.mode, None);
+        assert!(old.xattrs.is_empty());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_xattrs_round_trip() {
+        let temp_dir = tempdir().unwrap();
+        let original = temp_dir.path().join("tagged.txt");
+        let copy = temp_dir.path().join("copy.txt");
+        fs::write(&original, b"Tagged").unwrap();
+        fs::write(&copy, b"Tagged").unwrap();
+
+        let xattrs = BTreeMap::from([("user.mbbut.test".to_string(), b"value".to_vec())]);
+        write_xattrs(&original, &xattrs).unwrap();
+        let (captured, problems) = read_xattrs(&original);
+        assert!(problems.is_empty());
+        // File systems without user attributes capture none
+        if captured.is_empty() {
+            return;
+        }
+        assert_eq!(captured, xattrs);
+
+        write_xattrs(&copy, &captured).unwrap();
+        assert_eq!(read_xattrs(&copy).0, xattrs);
+    }
+}
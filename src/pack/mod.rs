@@ -0,0 +1,356 @@
+use crate::compression;
+use crate::config::Durability;
+use crate::throttle;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Folder inside the destination that holds pack files and their index
+pub const PACKS_DIR: &str = ".mbbut/packs";
+/// Append-only index with one JSON record per packed object
+const INDEX_FILE: &str = "index.jsonl";
+
+/// Where a packed object lives
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PackLocation {
+    /// File name of the pack inside the packs folder
+    pub pack: String,
+    pub offset: u64,
+    /// Compressed length in bytes
+    pub length: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct IndexRecord {
+    hash: String,
+    #[serde(flatten)]
+    location: PackLocation,
+}
+
+/// Maps object hashes to their location in the pack files
+#[derive(Debug, Default)]
+pub struct PackIndex {
+    locations: HashMap<String, PackLocation>,
+}
+
+impl PackIndex {
+    /// Load the index of the packs in the destination. Records cut short by a crash are
+    /// ignored, since the object they describe was never fully recorded, and so are records
+    /// that point past the end of their pack, whose bytes never made it to disk.
+    pub fn load(destination_root: &Path) -> Result<Self> {
+        let index_path = destination_root.join(PACKS_DIR).join(INDEX_FILE);
+        let file = match File::open(&index_path) {
+            Ok(file) => file,
+            Err(_) => return Ok(Self::default()),
+        };
+
+        let mut pack_sizes: HashMap<String, u64> = HashMap::new();
+        let mut locations = HashMap::new();
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            let Ok(record) = serde_json::from_str::<IndexRecord>(&line) else {
+                continue;
+            };
+            let pack_size = *pack_sizes.entry(record.location.pack.clone()).or_insert_with(|| {
+                let pack_file = destination_root.join(pack_path(&record.location.pack));
+                fs::metadata(pack_file).map_or(0, |metadata| metadata.len())
+            });
+            if record.location.offset + record.location.length <= pack_size {
+                locations.insert(record.hash, record.location);
+            }
+        }
+
+        Ok(Self { locations })
+    }
+
+    pub fn get(&self, hash: &str) -> Option<&PackLocation> {
+        self.locations.get(hash)
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.locations.contains_key(hash)
+    }
+}
+
+/// Path of a pack file relative to the destination root
+pub fn pack_path(pack: &str) -> PathBuf {
+    Path::new(PACKS_DIR).join(pack)
+}
+
+/// Read the compressed bytes of a packed object
+pub fn read_object(destination_root: &Path, location: &PackLocation) -> Result<Vec<u8>> {
+    let pack_file = destination_root.join(pack_path(&location.pack));
+    let mut file = File::open(&pack_file)
+        .with_context(|| format!("Missing pack {}", location.pack))?;
+    file.seek(SeekFrom::Start(location.offset))?;
+
+    let mut data = vec![0; location.length as usize];
+    file.read_exact(&mut data)?;
+    Ok(data)
+}
+
+struct OpenPack {
+    name: String,
+    file: File,
+    size: u64,
+}
+
+struct PackState {
+    index: PackIndex,
+    index_file: File,
+    /// Records of objects added since the last sync, written to the index file only once their
+    /// pack is on disk
+    unindexed: Vec<IndexRecord>,
+    current: Option<OpenPack>,
+    next_number: u32,
+}
+
+/// Appends compressed objects to pack files, starting a new pack whenever the current one
+/// would grow past the target size. Safe to share between worker threads.
+pub struct PackWriter {
+    destination_root: PathBuf,
+    target_size: u64,
+    durability: Durability,
+    state: Mutex<PackState>,
+}
+
+impl PackWriter {
+    pub fn open(destination_root: &Path, target_size: u64, durability: Durability) -> Result<Self> {
+        let packs_dir = destination_root.join(PACKS_DIR);
+        fs::create_dir_all(&packs_dir)?;
+
+        // Every run starts a fresh pack rather than appending to an older one
+        let mut next_number = 1;
+        for entry in fs::read_dir(&packs_dir)? {
+            let name = entry?.file_name();
+            let number = name
+                .to_str()
+                .and_then(|name| name.strip_prefix("pack-"))
+                .and_then(|name| name.strip_suffix(".pack"))
+                .and_then(|number| number.parse::<u32>().ok());
+            if let Some(number) = number {
+                next_number = next_number.max(number + 1);
+            }
+        }
+
+        let index_file = compression::open_line_log(&packs_dir.join(INDEX_FILE))?;
+
+        Ok(Self {
+            destination_root: destination_root.to_path_buf(),
+            target_size,
+            durability,
+            state: Mutex::new(PackState {
+                index: PackIndex::load(destination_root)?,
+                index_file,
+                unindexed: Vec::new(),
+                current: None,
+                next_number,
+            }),
+        })
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.state.lock().unwrap().index.contains(hash)
+    }
+
+    /// Append a compressed object to the current pack, returning false if an object with the
+    /// same hash is already packed
+    pub fn add(&self, hash: &str, compressed: &[u8]) -> Result<bool> {
+        let mut state = self.state.lock().unwrap();
+        if state.index.contains(hash) {
+            return Ok(false);
+        }
+
+        let length = compressed.len() as u64;
+        let is_full = match &state.current {
+            Some(pack) => pack.size > 0 && pack.size + length > self.target_size,
+            None => true,
+        };
+        if is_full {
+            // A pack is never written again once it's full
+            if let Some(full_pack) = &state.current {
+                self.sync_file(&full_pack.file)?;
+            }
+            let name = format!("pack-{:06}.pack", state.next_number);
+            state.next_number += 1;
+            let file = OpenOptions::new()
+                .create_new(true)
+                .append(true)
+                .open(self.destination_root.join(pack_path(&name)))?;
+            state.current = Some(OpenPack { name, file, size: 0 });
+        }
+
+        let pack = state.current.as_mut().context("No open pack")?;
+        throttle::writer(&mut pack.file).write_all(compressed)?;
+        let location = PackLocation {
+            pack: pack.name.clone(),
+            offset: pack.size,
+            length,
+        };
+        pack.size += length;
+
+        // Later objects with the same hash are deduplicated right away, but the object is only
+        // written to the index file by `sync`, once its bytes are on disk
+        state.index.locations.insert(hash.to_string(), location.clone());
+        state.unindexed.push(IndexRecord {
+            hash: hash.to_string(),
+            location,
+        });
+
+        Ok(true)
+    }
+
+    /// Flush the current pack to disk as the durability policy asks, then index the objects
+    /// added since the last sync and flush the index. The index never refers to bytes that
+    /// could still be lost.
+    pub fn sync(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(pack) = &state.current {
+            self.sync_file(&pack.file)?;
+        }
+
+        let mut lines = String::new();
+        for record in &state.unindexed {
+            lines.push_str(&serde_json::to_string(record)?);
+            lines.push('\n');
+        }
+        state.index_file.write_all(lines.as_bytes())?;
+        state.unindexed.clear();
+        self.sync_file(&state.index_file)?;
+
+        if cfg!(unix) && self.durability == Durability::Full {
+            File::open(self.destination_root.join(PACKS_DIR))?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    fn sync_file(&self, file: &File) -> Result<()> {
+        if self.durability != Durability::None {
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_pack_writer_add_and_read() {
+        let temp_dir = tempdir().unwrap();
+        let writer = PackWriter::open(temp_dir.path(), 1024, Durability::File).unwrap();
+
+        assert!(writer.add("hash_a", b"first object").unwrap());
+        assert!(writer.add("hash_b", b"second object").unwrap());
+        assert!(writer.contains("hash_a"));
+
+        // Adding the same hash again is a no-op
+        assert!(!writer.add("hash_a", b"first object").unwrap());
+
+        // Both objects are in the same pack and read back from the index once synced
+        assert!(PackIndex::load(temp_dir.path()).unwrap().get("hash_a").is_none());
+        writer.sync().unwrap();
+        let index = PackIndex::load(temp_dir.path()).unwrap();
+        let first = index.get("hash_a").unwrap();
+        let second = index.get("hash_b").unwrap();
+        assert_eq!(first.pack, "pack-000001.pack");
+        assert_eq!(second.pack, first.pack);
+        assert_eq!(second.offset, 12);
+        assert_eq!(read_object(temp_dir.path(), first).unwrap(), b"first object");
+        assert_eq!(read_object(temp_dir.path(), second).unwrap(), b"second object");
+    }
+
+    #[test]
+    fn test_pack_writer_rolls_over_at_target_size() {
+        let temp_dir = tempdir().unwrap();
+        let writer = PackWriter::open(temp_dir.path(), 20, Durability::File).unwrap();
+
+        writer.add("hash_a", &[1; 15]).unwrap();
+        writer.add("hash_b", &[2; 15]).unwrap();
+        // Objects larger than the target still get a pack of their own
+        writer.add("hash_c", &[3; 50]).unwrap();
+        writer.sync().unwrap();
+
+        let index = PackIndex::load(temp_dir.path()).unwrap();
+        assert_eq!(index.get("hash_a").unwrap().pack, "pack-000001.pack");
+        assert_eq!(index.get("hash_b").unwrap().pack, "pack-000002.pack");
+        assert_eq!(index.get("hash_c").unwrap().pack, "pack-000003.pack");
+        assert_eq!(read_object(temp_dir.path(), index.get("hash_c").unwrap()).unwrap(), [3; 50]);
+    }
+
+    #[test]
+    fn test_pack_writer_starts_new_pack_each_run() {
+        let temp_dir = tempdir().unwrap();
+        let first_run = PackWriter::open(temp_dir.path(), 1024, Durability::File).unwrap();
+        first_run.add("hash_a", b"first run").unwrap();
+        first_run.sync().unwrap();
+
+        // A second writer keeps the existing index and writes to a new pack
+        let writer = PackWriter::open(temp_dir.path(), 1024, Durability::File).unwrap();
+        assert!(writer.contains("hash_a"));
+        writer.add("hash_b", b"second run").unwrap();
+        writer.sync().unwrap();
+
+        let index = PackIndex::load(temp_dir.path()).unwrap();
+        assert_eq!(index.get("hash_b").unwrap().pack, "pack-000002.pack");
+    }
+
+    #[test]
+    fn test_pack_index_ignores_torn_records() {
+        let temp_dir = tempdir().unwrap();
+        let writer = PackWriter::open(temp_dir.path(), 1024, Durability::File).unwrap();
+        writer.add("hash_a", b"complete").unwrap();
+        writer.sync().unwrap();
+
+        // Simulate a crash in the middle of writing an index record
+        let index_path = temp_dir.path().join(PACKS_DIR).join(INDEX_FILE);
+        let mut index_file = OpenOptions::new().append(true).open(&index_path).unwrap();
+        index_file.write_all(br#"{"hash":"hash_b","pack":"pa"#).unwrap();
+
+        let index = PackIndex::load(temp_dir.path()).unwrap();
+        assert!(index.contains("hash_a"));
+        assert!(!index.contains("hash_b"));
+
+        // Records written after the torn one are still readable
+        let writer = PackWriter::open(temp_dir.path(), 1024, Durability::File).unwrap();
+        writer.add("hash_c", b"after the crash").unwrap();
+        writer.sync().unwrap();
+        let index = PackIndex::load(temp_dir.path()).unwrap();
+        assert!(index.contains("hash_c"));
+    }
+
+    #[test]
+    fn test_pack_index_ignores_objects_past_end_of_pack() {
+        let temp_dir = tempdir().unwrap();
+        let writer = PackWriter::open(temp_dir.path(), 1024, Durability::File).unwrap();
+        writer.add("hash_a", b"first object").unwrap();
+        writer.add("hash_b", b"second object").unwrap();
+        writer.sync().unwrap();
+
+        // Simulate a crash that lost the tail of the pack but kept the index
+        let pack_file = temp_dir.path().join(pack_path("pack-000001.pack"));
+        OpenOptions::new().write(true).open(&pack_file).unwrap().set_len(15).unwrap();
+
+        let index = PackIndex::load(temp_dir.path()).unwrap();
+        assert!(index.contains("hash_a"));
+        assert!(!index.contains("hash_b"));
+
+        // The next run packs the lost object again instead of deduplicating against it
+        let writer = PackWriter::open(temp_dir.path(), 1024, Durability::File).unwrap();
+        assert!(!writer.contains("hash_b"));
+        assert!(writer.add("hash_b", b"second object").unwrap());
+    }
+
+    #[test]
+    fn test_pack_index_load_missing() {
+        let temp_dir = tempdir().unwrap();
+        let index = PackIndex::load(temp_dir.path()).unwrap();
+        assert!(!index.contains("anything"));
+    }
+}
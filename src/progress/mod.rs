@@ -0,0 +1,234 @@
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use std::cell::RefCell;
+use std::io::{self, IsTerminal};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Files at least this large get a progress bar of their own
+pub const FILE_BAR_THRESHOLD: u64 = 64 * 1024 * 1024;
+/// How often a progress line is printed when stdout is not a terminal
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+thread_local! {
+    /// Progress of the file the current thread is backing up, if any
+    static CURRENT: RefCell<Option<(Progress, Option<ProgressBar>)>> = const { RefCell::new(None) };
+}
+
+struct Shared {
+    /// Bars drawn on the terminal, or `None` when progress is logged as plain lines
+    bars: Option<MultiProgress>,
+    overall: ProgressBar,
+    total_bytes: u64,
+    total_files: usize,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    files_done: AtomicUsize,
+    started_at: Instant,
+    last_logged_at: Mutex<Instant>,
+}
+
+/// Progress of a backup in bytes read from the source, with the transfer speed and the
+/// compression ratio so far. Drawn as bars on a terminal and logged as periodic lines otherwise.
+#[derive(Clone)]
+pub struct Progress {
+    shared: Arc<Shared>,
+}
+
+impl Progress {
+    pub fn new(total_bytes: u64, total_files: usize) -> Self {
+        let (bars, overall) = if io::stdout().is_terminal() {
+            let bars = MultiProgress::with_draw_target(ProgressDrawTarget::stdout());
+            let overall = bars.add(ProgressBar::new(total_bytes));
+            overall.set_style(
+                ProgressStyle::default_bar()
+                    .template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} {binary_bytes_per_sec} ({eta}) {msg}")
+                    .unwrap()
+                    .progress_chars("#>-"),
+            );
+            (Some(bars), overall)
+        } else {
+            (None, ProgressBar::hidden())
+        };
+
+        let now = Instant::now();
+        let progress = Self {
+            shared: Arc::new(Shared {
+                bars,
+                overall,
+                total_bytes,
+                total_files,
+                bytes_read: AtomicU64::new(0),
+                bytes_written: AtomicU64::new(0),
+                files_done: AtomicUsize::new(0),
+                started_at: now,
+                last_logged_at: Mutex::new(now),
+            }),
+        };
+        progress.shared.overall.set_message(progress.counts());
+        progress
+    }
+
+    /// Run `backup`, counting the bytes it reads and writes through `throttle` towards this
+    /// file and the overall progress
+    pub fn track<T>(&self, path: &Path, size: u64, backup: impl FnOnce() -> T) -> T {
+        let file_bar = if size >= FILE_BAR_THRESHOLD {
+            match &self.shared.bars {
+                Some(bars) => {
+                    let bar = bars.add(ProgressBar::new(size));
+                    bar.set_style(
+                        ProgressStyle::default_bar()
+                            .template("  {wide_msg} [{bar:30}] {bytes}/{total_bytes} {binary_bytes_per_sec}")
+                            .unwrap()
+                            .progress_chars("#>-"),
+                    );
+                    bar.set_message(path.display().to_string());
+                    Some(bar)
+                }
+                None => {
+                    println!("Backing up {} ({})", path.display(), HumanBytes(size));
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        CURRENT.with(|current| *current.borrow_mut() = Some((self.clone(), file_bar.clone())));
+        let result = backup();
+        CURRENT.with(|current| *current.borrow_mut() = None);
+
+        if let (Some(bars), Some(bar)) = (&self.shared.bars, file_bar) {
+            bar.finish_and_clear();
+            bars.remove(&bar);
+        }
+        result
+    }
+
+    /// Count a file as done, however many times it had to be read
+    pub fn file_done(&self) {
+        self.shared.files_done.fetch_add(1, Ordering::SeqCst);
+        self.shared.overall.set_message(self.counts());
+    }
+
+    fn add_read(&self, bytes: u64) {
+        self.shared.bytes_read.fetch_add(bytes, Ordering::SeqCst);
+        self.shared.overall.inc(bytes);
+        self.log_if_due();
+    }
+
+    fn add_written(&self, bytes: u64) {
+        self.shared.bytes_written.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    /// Files done and compression ratio so far
+    fn counts(&self) -> String {
+        let read = self.shared.bytes_read.load(Ordering::SeqCst);
+        let written = self.shared.bytes_written.load(Ordering::SeqCst);
+        let ratio = if written > 0 {
+            format!("{:.2}x", read as f64 / written as f64)
+        } else {
+            "-".to_string()
+        };
+        format!(
+            "{}/{} files, ratio {}",
+            self.shared.files_done.load(Ordering::SeqCst),
+            self.shared.total_files,
+            ratio
+        )
+    }
+
+    /// One line describing the progress so far, for logs
+    pub fn summary(&self) -> String {
+        let read = self.shared.bytes_read.load(Ordering::SeqCst);
+        let elapsed = self.shared.started_at.elapsed().as_secs_f64();
+        let speed = if elapsed > 0.0 { (read as f64 / elapsed) as u64 } else { 0 };
+        let percent = (read.min(self.shared.total_bytes) * 100)
+            .checked_div(self.shared.total_bytes)
+            .unwrap_or(100);
+        format!(
+            "Read {} of {} ({}%) at {}/s, {}",
+            HumanBytes(read),
+            HumanBytes(self.shared.total_bytes),
+            percent,
+            HumanBytes(speed),
+            self.counts()
+        )
+    }
+
+    fn log_if_due(&self) {
+        if self.shared.bars.is_some() {
+            return;
+        }
+        // Another thread is already logging
+        let Ok(mut last_logged_at) = self.shared.last_logged_at.try_lock() else {
+            return;
+        };
+        if last_logged_at.elapsed() >= LOG_INTERVAL {
+            *last_logged_at = Instant::now();
+            println!("{}", self.summary());
+        }
+    }
+
+    /// Stop drawing, leaving `message` as the final state
+    pub fn finish(&self, message: String) {
+        match &self.shared.bars {
+            Some(_) => self.shared.overall.finish_with_message(format!("{} ({})", message, self.counts())),
+            None => println!("{}. {}", message, self.summary()),
+        }
+    }
+
+    /// Stop drawing because the run stopped early
+    pub fn abandon(&self) {
+        match &self.shared.bars {
+            Some(_) => self.shared.overall.abandon_with_message("Stopped early"),
+            None => println!("Stopped early. {}", self.summary()),
+        }
+    }
+}
+
+/// Count `bytes` read from a source file towards the file the current thread is backing up
+pub fn record_read(bytes: usize) {
+    CURRENT.with(|current| {
+        if let Some((progress, file_bar)) = current.borrow().as_ref() {
+            progress.add_read(bytes as u64);
+            if let Some(bar) = file_bar {
+                bar.inc(bytes as u64);
+            }
+        }
+    });
+}
+
+/// Count `bytes` written to the destination towards the file the current thread is backing up
+pub fn record_written(bytes: usize) {
+    CURRENT.with(|current| {
+        if let Some((progress, _)) = current.borrow().as_ref() {
+            progress.add_written(bytes as u64);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress_counts_tracked_bytes() {
+        let progress = Progress::new(1000, 2);
+
+        progress.track(Path::new("a.txt"), 600, || {
+            record_read(600);
+            record_written(200);
+        });
+        progress.file_done();
+        // Bytes moved outside of a tracked file don't count
+        record_read(100);
+        record_written(100);
+
+        assert_eq!(progress.shared.bytes_read.load(Ordering::SeqCst), 600);
+        assert_eq!(progress.shared.bytes_written.load(Ordering::SeqCst), 200);
+        assert_eq!(progress.counts(), "1/2 files, ratio 3.00x");
+        assert!(progress.summary().starts_with("Read 600 B of 1000 B (60%)"));
+    }
+}
@@ -0,0 +1,329 @@
+use crate::compression;
+use crate::config::Durability;
+use crate::snapshot::{id_sequence, snapshot_id, unique_path};
+use crate::throttle::TransferError;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Folder inside the destination that holds a report for every run
+pub const REPORTS_DIR: &str = ".mbbut/reports";
+
+/// How a run ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RunStatus {
+    Completed,
+    /// Stopped early by Ctrl-C or SIGTERM, to be finished by `resume`
+    Interrupted,
+}
+
+/// Step of backing up a file that failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    /// Walking the source to find files
+    Walk,
+    /// Reading a source file
+    Read,
+    Compress,
+    /// Hashing a source file to see whether it changed
+    Hash,
+    /// Writing to the destination or the registry
+    Write,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Failed to {}", self.name())
+    }
+}
+
+impl Operation {
+    pub fn name(self) -> &'static str {
+        match self {
+            Operation::Walk => "walk",
+            Operation::Read => "read",
+            Operation::Compress => "compress",
+            Operation::Hash => "hash",
+            Operation::Write => "write",
+        }
+    }
+
+    /// Tag `error` with this operation unless it already carries one, for a call site whose
+    /// untagged errors all come from the same step
+    pub fn tag_untagged(self, error: anyhow::Error) -> anyhow::Error {
+        match Operation::of(&error) {
+            Some(_) => error,
+            None => error.context(self),
+        }
+    }
+
+    /// The operation `error` was tagged with, either by a throttled reader or writer or as
+    /// context, preferring the innermost tag
+    pub fn of(error: &anyhow::Error) -> Option<Operation> {
+        error
+            .chain()
+            .filter_map(|cause| cause.downcast_ref::<io::Error>())
+            .find_map(|error| error.get_ref()?.downcast_ref::<TransferError>())
+            .map(|error| error.operation)
+            .or_else(|| error.downcast_ref::<Operation>().copied())
+    }
+}
+
+/// A file that couldn't be backed up, or a part of the source that couldn't be walked
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileFailure {
+    pub path: PathBuf,
+    pub operation: Operation,
+    /// Kind of the underlying I/O error, e.g. `PermissionDenied`, or `Other`
+    pub kind: String,
+    pub error: String,
+}
+
+impl FileFailure {
+    pub fn new(path: PathBuf, operation: Operation, error: &anyhow::Error) -> Self {
+        Self {
+            path,
+            operation,
+            kind: error_kind(error),
+            error: format!("{:#}", error),
+        }
+    }
+}
+
+/// Error number of EIO, which `io::ErrorKind` has no stable name for
+#[cfg(unix)]
+const EIO: i32 = 5;
+
+/// Name of the kind of I/O error behind `error`, e.g. `PermissionDenied`, `InputOutput` for
+/// EIO, or `Other` if there is no I/O error behind it
+pub fn error_kind(error: &anyhow::Error) -> String {
+    let io_error = error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<io::Error>())
+        .map(|error| match error.get_ref().and_then(|inner| inner.downcast_ref::<TransferError>()) {
+            Some(transfer_error) => transfer_error.io_error(),
+            None => error,
+        });
+
+    match io_error {
+        #[cfg(unix)]
+        Some(error) if error.raw_os_error() == Some(EIO) => "InputOutput".to_string(),
+        Some(error) => format!("{:?}", error.kind()),
+        None => "Other".to_string(),
+    }
+}
+
+/// A file that failed with a transient error and was tried again
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileRetry {
+    pub path: PathBuf,
+    /// Tries in total, including the first one
+    pub attempts: u32,
+    /// Kind of the last error that was retried
+    pub kind: String,
+    pub error: String,
+    /// Whether a retry succeeded, otherwise the file is also listed as a failure
+    pub recovered: bool,
+}
+
+/// A file that was backed up without part of its metadata, e.g. an extended attribute that
+/// couldn't be read
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileWarning {
+    pub path: PathBuf,
+    pub warning: String,
+}
+
+/// Summary of a single `run` or `resume`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunReport {
+    pub id: String,
+    /// Seconds since the Unix epoch
+    pub started_at: u64,
+    pub finished_at: u64,
+    pub status: RunStatus,
+    pub files_processed: usize,
+    /// Files that still need backing up because the run stopped early
+    pub files_remaining: usize,
+    #[serde(default)]
+    pub failures: Vec<FileFailure>,
+    #[serde(default)]
+    pub retries: Vec<FileRetry>,
+    /// Files that kept changing while they were read, so their copies may be torn
+    #[serde(default)]
+    pub inconsistent_files: Vec<PathBuf>,
+    /// Files that were backed up without part of their metadata
+    #[serde(default)]
+    pub warnings: Vec<FileWarning>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl RunReport {
+    /// Start a report for a run beginning now
+    pub fn start() -> Self {
+        let started_at = now();
+        Self {
+            id: snapshot_id(started_at),
+            started_at,
+            finished_at: started_at,
+            status: RunStatus::Completed,
+            files_processed: 0,
+            files_remaining: 0,
+            failures: Vec::new(),
+            retries: Vec::new(),
+            inconsistent_files: Vec::new(),
+            warnings: Vec::new(),
+        }
+    }
+
+    /// Stamp the report with the end of the run and save it into the destination's report
+    /// folder, returning its path
+    pub fn finish(&mut self, status: RunStatus, destination_root: &Path, durability: Durability) -> Result<PathBuf> {
+        self.status = status;
+        self.finished_at = now();
+
+        let reports_dir = destination_root.join(REPORTS_DIR);
+        fs::create_dir_all(&reports_dir)?;
+
+        let (id, path) = unique_path(&reports_dir, &self.id, "json");
+        self.id = id;
+        let content = serde_json::to_string_pretty(self)?;
+        compression::write_atomically(&path, content.as_bytes(), durability)?;
+        Ok(path)
+    }
+}
+
+/// What a run would do, worked out without writing anything
+#[derive(Debug, Default, Serialize)]
+pub struct DryRunReport {
+    pub new_files: Vec<PathBuf>,
+    pub changed_files: Vec<PathBuf>,
+    /// Files with unchanged content that are backed up again because an earlier run failed,
+    /// was interrupted or left a possibly torn copy of them
+    pub retried_files: Vec<PathBuf>,
+    pub unchanged_files: Vec<PathBuf>,
+    pub excluded_files: Vec<PathBuf>,
+    pub deleted_files: Vec<PathBuf>,
+    /// Size of the new, changed and retried files
+    pub total_bytes: u64,
+    /// Size of the new, changed and retried files once compressed, estimated from a sample of each
+    pub estimated_compressed_bytes: u64,
+    /// Files that couldn't be hashed and parts of the source that couldn't be walked
+    pub failures: Vec<FileFailure>,
+}
+
+/// Load the report of the most recent run, if any
+pub fn latest_report(destination_root: &Path) -> Result<Option<RunReport>> {
+    let reports_dir = destination_root.join(REPORTS_DIR);
+    if !reports_dir.exists() {
+        return Ok(None);
+    }
+
+    let mut latest: Option<RunReport> = None;
+    for entry in fs::read_dir(&reports_dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("json") {
+            continue;
+        }
+        let content = fs::read_to_string(&path)?;
+        let report: RunReport = serde_json::from_str(&content)
+            .with_context(|| format!("Failed to load run report {}", path.display()))?;
+
+        let is_newer = latest
+            .as_ref()
+            .is_none_or(|latest| {
+                (report.started_at, id_sequence(&report.id)) > (latest.started_at, id_sequence(&latest.id))
+            });
+        if is_newer {
+            latest = Some(report);
+        }
+    }
+
+    Ok(latest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::throttle;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_file_failure_operation_and_kind() {
+        let temp_dir = tempdir().unwrap();
+        let missing = temp_dir.path().join("missing.txt");
+
+        // Errors of throttled readers are tagged as reads, however they're wrapped later
+        let error = throttle::open(&missing)
+            .context("Failed to back up")
+            .unwrap_err();
+        assert_eq!(Operation::of(&error), Some(Operation::Read));
+        let failure = FileFailure::new(missing.clone(), Operation::Read, &error);
+        assert_eq!(failure.kind, "NotFound");
+        assert!(failure.error.starts_with("Failed to back up: "));
+
+        // Otherwise the operation is taken from the context
+        let error = fs::read(&missing).context(Operation::Hash).unwrap_err();
+        assert_eq!(Operation::of(&error), Some(Operation::Hash));
+
+        // A fallback tag doesn't override one that's already there
+        let error = Operation::Write.tag_untagged(error);
+        assert_eq!(Operation::of(&error), Some(Operation::Hash));
+        let error = Operation::Write.tag_untagged(anyhow::anyhow!("No such pack"));
+        assert_eq!(Operation::of(&error), Some(Operation::Write));
+        assert_eq!(Operation::of(&anyhow::anyhow!("Untagged")), None);
+
+        // EIO has a name of its own so it can be retried
+        #[cfg(unix)]
+        assert_eq!(error_kind(&io::Error::from_raw_os_error(EIO).into()), "InputOutput");
+
+        // Errors without an I/O error behind them are of kind Other
+        let failure = FileFailure::new(missing, Operation::Write, &anyhow::anyhow!("Untagged"));
+        assert_eq!(failure.kind, "Other");
+    }
+
+    #[test]
+    fn test_latest_report() {
+        let temp_dir = tempdir().unwrap();
+        assert!(latest_report(temp_dir.path()).unwrap().is_none());
+
+        // An older completed run and a newer one that was interrupted
+        let mut older = RunReport::start();
+        older.started_at -= 60;
+        older.id = snapshot_id(older.started_at);
+        older.finish(RunStatus::Completed, temp_dir.path(), Durability::File).unwrap();
+
+        let mut newer = RunReport::start();
+        newer.files_processed = 3;
+        newer.files_remaining = 7;
+        newer.finish(RunStatus::Interrupted, temp_dir.path(), Durability::File).unwrap();
+
+        let latest = latest_report(temp_dir.path()).unwrap().unwrap();
+        assert_eq!(latest.id, newer.id);
+        assert_eq!(latest.status, RunStatus::Interrupted);
+        assert_eq!(latest.files_remaining, 7);
+        assert!(latest.finished_at >= latest.started_at);
+
+        // Runs in the same second are ordered by their number, so `-10` is later than `-2`
+        for _ in 0..11 {
+            let mut next = RunReport::start();
+            next.started_at = newer.started_at;
+            next.id = snapshot_id(newer.started_at);
+            next.finish(RunStatus::Completed, temp_dir.path(), Durability::File).unwrap();
+        }
+        let latest = latest_report(temp_dir.path()).unwrap().unwrap();
+        assert_eq!(latest.id, format!("{}-11", snapshot_id(newer.started_at)));
+    }
+}
@@ -0,0 +1,339 @@
+use crate::compression;
+use crate::pack::{self, PackIndex};
+use crate::snapshot::{locate_object, Snapshot, SnapshotEntry};
+use crate::store;
+use anyhow::{Context, Result};
+use blake3::Hasher;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Outcome of checking every file in a snapshot against its recorded hash
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub verified: usize,
+    /// Files that couldn't be read back or whose content didn't match, with the reason
+    pub failures: Vec<(PathBuf, String)>,
+}
+
+/// Write the original content of `entry` to `writer`, reading it from a pack, a chunk list
+/// or a compressed file as appropriate
+fn read_entry<W: Write>(
+    destination_root: &Path,
+    entry: &SnapshotEntry,
+    pack_index: &PackIndex,
+    writer: &mut W,
+) -> Result<()> {
+    if entry.packed {
+        let location = pack_index
+            .get(&entry.hash)
+            .with_context(|| format!("No packed object found for {}", entry.path.display()))?;
+        let compressed = pack::read_object(destination_root, location)?;
+        return compression::decompress_stream(&compressed[..], writer);
+    }
+
+    let object_file = locate_object(destination_root, entry)?;
+    if entry.chunked {
+        store::read_chunked(destination_root, &object_file, writer)
+    } else {
+        compression::decompress_stream(File::open(&object_file)?, writer)
+    }
+}
+
+/// Create a symlink at `link` pointing to `target`
+#[cfg(unix)]
+fn create_symlink(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+/// Create a symlink at `link` pointing to `target`. Windows tells links to files and to
+/// directories apart, so a link whose target is missing is created as a link to a file.
+#[cfg(windows)]
+fn create_symlink(target: &Path, link: &Path) -> io::Result<()> {
+    let resolved = link.parent().map_or_else(|| target.to_path_buf(), |parent| parent.join(target));
+    if resolved.is_dir() {
+        std::os::windows::fs::symlink_dir(target, link)
+    } else {
+        std::os::windows::fs::symlink_file(target, link)
+    }
+}
+
+/// Restore every file and symlink in `snapshot` from `destination_root` into `target`,
+/// returning the number restored. Files get their recorded metadata back, including their
+/// owner and group if `restore_owner` is set.
+pub fn restore_snapshot(
+    snapshot: &Snapshot,
+    destination_root: &Path,
+    target: &Path,
+    restore_owner: bool,
+) -> Result<usize> {
+    let pack_index = PackIndex::load(destination_root)?;
+    let mut restored = 0;
+
+    for entry in &snapshot.files {
+        let target_file = target.join(&entry.path);
+        if let Some(parent) = target_file.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut writer = BufWriter::new(File::create(&target_file)?);
+        read_entry(destination_root, entry, &pack_index, &mut writer)
+            .and_then(|_| Ok(writer.flush()?))
+            .with_context(|| format!("Failed to restore {}", entry.path.display()))?;
+        drop(writer);
+        if let Some(metadata) = &entry.metadata {
+            metadata
+                .apply(&target_file, restore_owner)
+                .with_context(|| format!("Failed to restore metadata of {}", entry.path.display()))?;
+        }
+        restored += 1;
+
+        for link_path in &entry.links {
+            let link = target.join(link_path);
+            if let Some(parent) = link.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let result = match fs::symlink_metadata(&link) {
+                Ok(_) => fs::remove_file(&link).and_then(|_| fs::hard_link(&target_file, &link)),
+                Err(_) => fs::hard_link(&target_file, &link),
+            };
+            result.with_context(|| format!("Failed to restore hard link {}", link_path.display()))?;
+            restored += 1;
+        }
+    }
+
+    for entry in &snapshot.symlinks {
+        let link = target.join(&entry.path);
+        if let Some(parent) = link.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // Links are restored as they were, wherever they point
+        let result = match fs::symlink_metadata(&link) {
+            Ok(_) => fs::remove_file(&link).and_then(|_| create_symlink(&entry.target, &link)),
+            Err(_) => create_symlink(&entry.target, &link),
+        };
+        result.with_context(|| format!("Failed to restore link {}", entry.path.display()))?;
+        restored += 1;
+    }
+
+    Ok(restored)
+}
+
+/// Read back every file in `snapshot` and check that its content matches the recorded hash
+pub fn verify_snapshot(snapshot: &Snapshot, destination_root: &Path) -> Result<VerifyReport> {
+    let pack_index = PackIndex::load(destination_root)?;
+    let mut report = VerifyReport::default();
+
+    for entry in &snapshot.files {
+        let mut hasher = Hasher::new();
+        match read_entry(destination_root, entry, &pack_index, &mut hasher) {
+            Ok(()) => {
+                let hash = hasher.finalize().to_hex().to_string();
+                if hash == entry.hash {
+                    report.verified += 1;
+                } else {
+                    let reason = "content does not match its hash".to_string();
+                    report.failures.push((entry.path.clone(), reason));
+                }
+            }
+            Err(e) => report.failures.push((entry.path.clone(), format!("{:#}", e))),
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Durability;
+    use crate::hashing::hash_file;
+    use crate::pack::PackWriter;
+    use crate::snapshot::SymlinkEntry;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_restore_snapshot() {
+        let source_dir = tempdir().unwrap();
+        let dest_dir = tempdir().unwrap();
+        let target_dir = tempdir().unwrap();
+
+        // Store a compressed object for a nested file
+        let source_file = source_dir.path().join("original.txt");
+        fs::write(&source_file, b"Restore me").unwrap();
+        let object = PathBuf::from("docs/file.txt.zst");
+        compression::compress_file(&source_file, dest_dir.path().join(&object), Durability::File).unwrap();
+
+        let mut snapshot = Snapshot::new(Vec::new());
+        snapshot.files.push(SnapshotEntry {
+            path: PathBuf::from("docs/file.txt"),
+            hash: "hash".to_string(),
+            size: 10,
+            object,
+            chunked: false,
+            packed: false,
+            links: Vec::new(),
+            metadata: None,
+        });
+
+        // Restore recreates the directory structure and content
+        let restored = restore_snapshot(&snapshot, dest_dir.path(), target_dir.path(), true).unwrap();
+        assert_eq!(restored, 1);
+        assert_eq!(fs::read(target_dir.path().join("docs/file.txt")).unwrap(), b"Restore me");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_restore_snapshot_hardlinks() {
+        use std::os::unix::fs::MetadataExt;
+
+        let source_dir = tempdir().unwrap();
+        let dest_dir = tempdir().unwrap();
+        let target_dir = tempdir().unwrap();
+        let source_file = source_dir.path().join("cache.bin");
+        fs::write(&source_file, b"Shared content").unwrap();
+        let object = PathBuf::from("a/cache.bin.zst");
+        compression::compress_file(&source_file, dest_dir.path().join(&object), Durability::File).unwrap();
+
+        let mut snapshot = Snapshot::new(Vec::new());
+        snapshot.files.push(SnapshotEntry {
+            path: PathBuf::from("a/cache.bin"),
+            hash: "hash".to_string(),
+            size: 14,
+            object,
+            chunked: false,
+            packed: false,
+            links: vec![PathBuf::from("b/cache.bin"), PathBuf::from("c/cache.bin")],
+            metadata: None,
+        });
+
+        // Every path of the group is restored as a link to the same file
+        let restored = restore_snapshot(&snapshot, dest_dir.path(), target_dir.path(), true).unwrap();
+        assert_eq!(restored, 3);
+        let metadata = fs::metadata(target_dir.path().join("a/cache.bin")).unwrap();
+        assert_eq!(metadata.nlink(), 3);
+        for link in ["b/cache.bin", "c/cache.bin"] {
+            let link_metadata = fs::metadata(target_dir.path().join(link)).unwrap();
+            assert_eq!(link_metadata.ino(), metadata.ino());
+        }
+        assert_eq!(fs::read(target_dir.path().join("c/cache.bin")).unwrap(), b"Shared content");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_restore_snapshot_symlinks() {
+        let dest_dir = tempdir().unwrap();
+        let target_dir = tempdir().unwrap();
+
+        let mut snapshot = Snapshot::new(Vec::new());
+        for (path, target) in [("docs/latest", "v2/notes.txt"), ("dangling", "/no/such/file")] {
+            snapshot.symlinks.push(SymlinkEntry {
+                path: PathBuf::from(path),
+                target: PathBuf::from(target),
+            });
+        }
+        // A file already in the way is replaced
+        fs::write(target_dir.path().join("dangling"), b"Old").unwrap();
+
+        let restored = restore_snapshot(&snapshot, dest_dir.path(), target_dir.path(), true).unwrap();
+        assert_eq!(restored, 2);
+        assert_eq!(
+            fs::read_link(target_dir.path().join("docs/latest")).unwrap(),
+            PathBuf::from("v2/notes.txt")
+        );
+        assert_eq!(
+            fs::read_link(target_dir.path().join("dangling")).unwrap(),
+            PathBuf::from("/no/such/file")
+        );
+    }
+
+    #[test]
+    fn test_restore_snapshot_missing_object() {
+        let dest_dir = tempdir().unwrap();
+        let target_dir = tempdir().unwrap();
+
+        let mut snapshot = Snapshot::new(Vec::new());
+        snapshot.files.push(SnapshotEntry {
+            path: PathBuf::from("missing.txt"),
+            hash: "hash".to_string(),
+            size: 10,
+            object: PathBuf::from("missing.txt.zst"),
+            chunked: false,
+            packed: false,
+            links: Vec::new(),
+            metadata: None,
+        });
+
+        assert!(restore_snapshot(&snapshot, dest_dir.path(), target_dir.path(), true).is_err());
+    }
+
+    #[test]
+    fn test_restore_and_verify_packed_snapshot() {
+        let dest_dir = tempdir().unwrap();
+        let target_dir = tempdir().unwrap();
+
+        // Pack an object directly
+        let content = b"Packed file content";
+        let hash = blake3::hash(content).to_hex().to_string();
+        let packs = PackWriter::open(dest_dir.path(), 1024, Durability::File).unwrap();
+        packs.add(&hash, &compression::compress_bytes(content).unwrap()).unwrap();
+        packs.sync().unwrap();
+
+        let mut snapshot = Snapshot::new(Vec::new());
+        snapshot.files.push(SnapshotEntry {
+            path: PathBuf::from("packed.txt"),
+            hash,
+            size: content.len() as u64,
+            object: pack::pack_path("pack-000001.pack"),
+            chunked: false,
+            packed: true,
+            links: Vec::new(),
+            metadata: None,
+        });
+
+        // Both restore and verify read the object out of the pack
+        restore_snapshot(&snapshot, dest_dir.path(), target_dir.path(), true).unwrap();
+        assert_eq!(fs::read(target_dir.path().join("packed.txt")).unwrap(), content);
+
+        let report = verify_snapshot(&snapshot, dest_dir.path()).unwrap();
+        assert_eq!(report.verified, 1);
+        assert!(report.failures.is_empty());
+    }
+
+    #[test]
+    fn test_verify_snapshot_detects_problems() {
+        let source_dir = tempdir().unwrap();
+        let dest_dir = tempdir().unwrap();
+
+        // A correctly stored file, one recorded with the wrong hash, and one that is missing
+        let source_file = source_dir.path().join("good.txt");
+        fs::write(&source_file, b"Good content").unwrap();
+        compression::compress_file(&source_file, dest_dir.path().join("good.txt.zst"), Durability::File).unwrap();
+        compression::compress_file(&source_file, dest_dir.path().join("bad.txt.zst"), Durability::File).unwrap();
+
+        let mut snapshot = Snapshot::new(Vec::new());
+        for (path, hash) in [
+            ("good.txt", hash_file(&source_file).unwrap()),
+            ("bad.txt", "not_the_hash".to_string()),
+            ("missing.txt", "hash".to_string()),
+        ] {
+            snapshot.files.push(SnapshotEntry {
+                path: PathBuf::from(path),
+                hash,
+                size: 12,
+                object: PathBuf::from(format!("{}.zst", path)),
+                chunked: false,
+                packed: false,
+                links: Vec::new(),
+                metadata: None,
+            });
+        }
+
+        let report = verify_snapshot(&snapshot, dest_dir.path()).unwrap();
+        assert_eq!(report.verified, 1);
+        assert_eq!(report.failures.len(), 2);
+        assert_eq!(report.failures[0].0, PathBuf::from("bad.txt"));
+        assert_eq!(report.failures[1].0, PathBuf::from("missing.txt"));
+    }
+}
@@ -0,0 +1,329 @@
+use crate::compression;
+use crate::config::{Durability, Source};
+use crate::metadata::FileMetadata;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Folder inside the destination that holds snapshot manifests
+pub const SNAPSHOTS_DIR: &str = ".mbbut/snapshots";
+/// Folder inside the destination that holds versions replaced by newer backups
+pub const VERSIONS_DIR: &str = ".mbbut/versions";
+/// Folder inside the destination that holds copies of files deleted from the source
+pub const ATTIC_DIR: &str = ".mbbut/attic";
+
+/// A file as it was at the time of a snapshot
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotEntry {
+    /// Path inside the backup: the source's destination prefix followed by the path relative
+    /// to the source
+    pub path: PathBuf,
+    pub hash: String,
+    /// Uncompressed size in bytes
+    pub size: u64,
+    /// Path of the stored object relative to the destination root, when it was written
+    pub object: PathBuf,
+    /// Whether `object` is a chunk list rather than a compressed file
+    #[serde(default)]
+    pub chunked: bool,
+    /// Whether the object is stored in a pack file, found through the pack index by hash
+    #[serde(default)]
+    pub packed: bool,
+    /// Other paths inside the backup that were hard links to this file, restored as hard links
+    /// to it
+    #[serde(default)]
+    pub links: Vec<PathBuf>,
+    /// Timestamps, permissions, ownership and extended attributes of the file, reapplied on
+    /// restore. Missing for files backed up before metadata was captured.
+    #[serde(default)]
+    pub metadata: Option<FileMetadata>,
+}
+
+/// A symbolic link as it was at the time of a snapshot
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SymlinkEntry {
+    /// Path inside the backup, like `SnapshotEntry::path`
+    pub path: PathBuf,
+    /// Where the link points, exactly as it was read from the link
+    pub target: PathBuf,
+}
+
+/// Point-in-time listing of every backed up file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub id: String,
+    /// Seconds since the Unix epoch
+    pub created_at: u64,
+    /// The folders that were backed up and where in the backup each one went
+    #[serde(default)]
+    pub sources: Vec<Source>,
+    pub files: Vec<SnapshotEntry>,
+    #[serde(default)]
+    pub symlinks: Vec<SymlinkEntry>,
+}
+
+impl Snapshot {
+    /// Create an empty snapshot stamped with the current time
+    pub fn new(sources: Vec<Source>) -> Self {
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        Self {
+            id: snapshot_id(created_at),
+            created_at,
+            sources,
+            files: Vec::new(),
+            symlinks: Vec::new(),
+        }
+    }
+
+    pub fn load_from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let content = fs::read_to_string(path)?;
+        let snapshot: Snapshot = serde_json::from_str(&content)?;
+        Ok(snapshot)
+    }
+
+    /// Save the manifest into the destination's snapshot folder, returning its path
+    pub fn save(&mut self, destination_root: &Path, durability: Durability) -> Result<PathBuf> {
+        let snapshots_dir = destination_root.join(SNAPSHOTS_DIR);
+        fs::create_dir_all(&snapshots_dir)?;
+
+        let (id, path) = unique_path(&snapshots_dir, &self.id, "json");
+        self.id = id;
+        let content = serde_json::to_string(self)?;
+        compression::write_atomically(&path, content.as_bytes(), durability)?;
+        Ok(path)
+    }
+
+    /// Total uncompressed size of the files in the snapshot
+    pub fn total_size(&self) -> u64 {
+        self.files.iter().map(|entry| entry.size).sum()
+    }
+
+    /// Human readable UTC creation time
+    pub fn created_at_display(&self) -> String {
+        let time = UNIX_EPOCH + Duration::from_secs(self.created_at);
+        humantime::format_rfc3339_seconds(time).to_string()
+    }
+}
+
+/// Filesystem safe id derived from the creation time, e.g. `20240131T235959Z`
+pub fn snapshot_id(created_at: u64) -> String {
+    let time = UNIX_EPOCH + Duration::from_secs(created_at);
+    humantime::format_rfc3339_seconds(time)
+        .to_string()
+        .replace(['-', ':'], "")
+}
+
+/// Id and path of a file in `dir` named after `base_id` with `extension` that doesn't exist
+/// yet. Two runs within the same second get distinct ids, e.g. `20240131T235959Z-1`.
+pub fn unique_path(dir: &Path, base_id: &str, extension: &str) -> (String, PathBuf) {
+    let mut id = base_id.to_string();
+    let mut suffix = 1;
+    while dir.join(format!("{}.{}", id, extension)).exists() {
+        id = format!("{}-{}", base_id, suffix);
+        suffix += 1;
+    }
+    let path = dir.join(format!("{}.{}", id, extension));
+    (id, path)
+}
+
+/// Position of a run among those started in the same second: 0 for the first, then the
+/// number `unique_path` added to its id. Compared as a number, so `-10` comes after `-2`.
+pub fn id_sequence(id: &str) -> u32 {
+    id.rsplit_once('-')
+        .and_then(|(_, suffix)| suffix.parse().ok())
+        .unwrap_or(0)
+}
+
+/// Load every snapshot in the destination, oldest first
+pub fn list_snapshots(destination_root: &Path) -> Result<Vec<Snapshot>> {
+    let snapshots_dir = destination_root.join(SNAPSHOTS_DIR);
+    if !snapshots_dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut snapshots = Vec::new();
+    for entry in fs::read_dir(&snapshots_dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("json") {
+            continue;
+        }
+        let snapshot = Snapshot::load_from_file(&path)
+            .with_context(|| format!("Failed to load snapshot {}", path.display()))?;
+        snapshots.push(snapshot);
+    }
+
+    snapshots.sort_by_key(|snapshot| (snapshot.created_at, id_sequence(&snapshot.id)));
+    Ok(snapshots)
+}
+
+/// Find a snapshot by id, or the latest one when no id is given
+pub fn find_snapshot(destination_root: &Path, id: Option<&str>) -> Result<Snapshot> {
+    let snapshots = list_snapshots(destination_root)?;
+    match id {
+        Some(id) => snapshots
+            .into_iter()
+            .find(|snapshot| snapshot.id == id)
+            .with_context(|| format!("Snapshot {} not found", id)),
+        None => snapshots.into_iter().last().context("No snapshots found"),
+    }
+}
+
+/// Path of the stored version with `hash` once it has been replaced by a newer one
+pub fn version_path(destination_root: &Path, hash: &str) -> PathBuf {
+    destination_root.join(VERSIONS_DIR).join(format!("{}.zst", hash))
+}
+
+/// Move the object at `object_file` into the versions folder so snapshots that point at it
+/// stay restorable after it is overwritten or removed
+pub fn retire_object(destination_root: &Path, object_file: &Path, hash: &str) -> Result<()> {
+    if !object_file.exists() {
+        return Ok(());
+    }
+
+    let version_file = version_path(destination_root, hash);
+    if version_file.exists() {
+        // The same content was retired before, so this copy isn't needed
+        fs::remove_file(object_file)?;
+        return Ok(());
+    }
+
+    if let Some(parent) = version_file.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::rename(object_file, version_file)?;
+    Ok(())
+}
+
+/// Find where the object for `entry` is stored now. It may have been retired to the versions
+/// folder or moved to the attic since the snapshot was taken.
+pub fn locate_object(destination_root: &Path, entry: &SnapshotEntry) -> Result<PathBuf> {
+    // Chunk lists are content-addressed and never move
+    if entry.chunked {
+        let chunk_list = destination_root.join(&entry.object);
+        if chunk_list.exists() {
+            return Ok(chunk_list);
+        }
+        bail!("No chunk list found for {}", entry.path.display());
+    }
+
+    let candidates = [
+        version_path(destination_root, &entry.hash),
+        destination_root.join(&entry.object),
+        destination_root.join(ATTIC_DIR).join(&entry.object),
+    ];
+
+    candidates
+        .into_iter()
+        .find(|candidate| candidate.exists())
+        .with_context(|| format!("No stored object found for {}", entry.path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn entry(path: &str, hash: &str, size: u64) -> SnapshotEntry {
+        SnapshotEntry {
+            path: PathBuf::from(path),
+            hash: hash.to_string(),
+            size,
+            object: PathBuf::from(format!("{}.zst", path)),
+            chunked: false,
+            packed: false,
+            links: Vec::new(),
+            metadata: None,
+        }
+    }
+
+    #[test]
+    fn test_snapshot_id_format() {
+        assert_eq!(snapshot_id(0), "19700101T000000Z");
+        assert_eq!(snapshot_id(1_700_000_000), "20231114T221320Z");
+        assert_eq!(id_sequence("20231114T221320Z"), 0);
+        assert_eq!(id_sequence("20231114T221320Z-10"), 10);
+    }
+
+    #[test]
+    fn test_snapshot_save_and_list() {
+        let temp_dir = tempdir().unwrap();
+
+        // No snapshots before anything is saved
+        assert!(list_snapshots(temp_dir.path()).unwrap().is_empty());
+
+        // Save two snapshots created in the same second
+        let mut first = Snapshot::new(Vec::new());
+        first.files.push(entry("a.txt", "hash_a", 10));
+        first.files.push(entry("b.txt", "hash_b", 20));
+        first.save(temp_dir.path(), Durability::File).unwrap();
+
+        let mut second = Snapshot::new(Vec::new());
+        second.created_at = first.created_at;
+        second.id = first.id.clone();
+        second.save(temp_dir.path(), Durability::File).unwrap();
+        assert_ne!(first.id, second.id);
+
+        // Both are listed, oldest first, with their contents intact
+        let snapshots = list_snapshots(temp_dir.path()).unwrap();
+        assert_eq!(snapshots.len(), 2);
+        assert_eq!(snapshots[0].id, first.id);
+        assert_eq!(snapshots[0].files, first.files);
+        assert_eq!(snapshots[0].total_size(), 30);
+        assert_eq!(snapshots[1].id, second.id);
+
+        // Later runs in the same second stay in order past the ninth
+        for _ in 0..10 {
+            let mut next = Snapshot::new(Vec::new());
+            next.created_at = first.created_at;
+            next.id = first.id.clone();
+            next.save(temp_dir.path(), Durability::File).unwrap();
+        }
+        let latest = find_snapshot(temp_dir.path(), None).unwrap();
+        assert_eq!(latest.id, format!("{}-11", first.id));
+    }
+
+    #[test]
+    fn test_find_snapshot() {
+        let temp_dir = tempdir().unwrap();
+        assert!(find_snapshot(temp_dir.path(), None).is_err());
+
+        let mut older = Snapshot::new(Vec::new());
+        older.created_at -= 60;
+        older.id = snapshot_id(older.created_at);
+        older.save(temp_dir.path(), Durability::File).unwrap();
+        let mut newer = Snapshot::new(Vec::new());
+        newer.save(temp_dir.path(), Durability::File).unwrap();
+
+        // Latest by default, or by id
+        assert_eq!(find_snapshot(temp_dir.path(), None).unwrap().id, newer.id);
+        assert_eq!(find_snapshot(temp_dir.path(), Some(&older.id)).unwrap().id, older.id);
+        assert!(find_snapshot(temp_dir.path(), Some("missing")).is_err());
+    }
+
+    #[test]
+    fn test_retire_and_locate_object() {
+        let temp_dir = tempdir().unwrap();
+        let snapshot_entry = entry("a.txt", "hash_a", 10);
+        let object_file = temp_dir.path().join("a.txt.zst");
+
+        // Nothing stored yet
+        assert!(locate_object(temp_dir.path(), &snapshot_entry).is_err());
+
+        // The object is found where it was written
+        fs::write(&object_file, b"version a").unwrap();
+        assert_eq!(locate_object(temp_dir.path(), &snapshot_entry).unwrap(), object_file);
+
+        // Once retired it is found in the versions folder
+        retire_object(temp_dir.path(), &object_file, "hash_a").unwrap();
+        fs::write(&object_file, b"version b").unwrap();
+        let located = locate_object(temp_dir.path(), &snapshot_entry).unwrap();
+        assert_eq!(located, version_path(temp_dir.path(), "hash_a"));
+        assert_eq!(fs::read(located).unwrap(), b"version a");
+    }
+}
@@ -0,0 +1,349 @@
+use crate::compression;
+use crate::config::Durability;
+use crate::pack::PackWriter;
+use crate::throttle;
+use anyhow::{bail, Context, Result};
+use blake3::Hasher;
+use fastcdc::v2020::StreamCDC;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Folder inside the destination that holds content-addressed objects
+pub const OBJECTS_DIR: &str = ".mbbut/objects";
+/// Folder inside the destination that holds content-defined chunks
+pub const CHUNKS_DIR: &str = ".mbbut/chunks";
+/// Folder inside the destination that holds the chunk list of each chunked file
+pub const CHUNK_LISTS_DIR: &str = ".mbbut/chunklists";
+
+/// Files at least this large are split into chunks in the chunked layout
+pub const CHUNKING_THRESHOLD: u64 = 4 * 1024 * 1024;
+const CHUNK_MIN_SIZE: u32 = 256 * 1024;
+const CHUNK_AVG_SIZE: u32 = 1024 * 1024;
+const CHUNK_MAX_SIZE: u32 = 4 * 1024 * 1024;
+
+/// Files up to this size are appended to pack files in the packed layout
+pub const PACKING_THRESHOLD: u64 = 1024 * 1024;
+
+/// Result of storing a file in the object store
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredObject {
+    pub hash: String,
+    /// False when an object with the same content was already stored
+    pub newly_stored: bool,
+}
+
+/// A chunk of a file, in the order it appears
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkRef {
+    pub hash: String,
+    /// Uncompressed length in bytes
+    pub length: u64,
+}
+
+/// The chunks that make up a chunked file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkList {
+    pub chunks: Vec<ChunkRef>,
+}
+
+/// Path of `hash` inside `dir`, fanned out into folders named after the first two hex digits
+/// to keep directories small
+fn fanned_out_path(dir: &str, hash: &str, extension: &str) -> PathBuf {
+    let prefix = hash.get(..2).unwrap_or(hash);
+    Path::new(dir)
+        .join(prefix)
+        .join(format!("{}.{}", hash, extension))
+}
+
+/// Path of the object with `hash` relative to the destination root
+pub fn object_path(hash: &str) -> PathBuf {
+    fanned_out_path(OBJECTS_DIR, hash, "zst")
+}
+
+/// Path of the chunk with `hash` relative to the destination root
+pub fn chunk_path(hash: &str) -> PathBuf {
+    fanned_out_path(CHUNKS_DIR, hash, "zst")
+}
+
+/// Path of the chunk list for the file with `hash` relative to the destination root
+pub fn chunk_list_path(hash: &str) -> PathBuf {
+    fanned_out_path(CHUNK_LISTS_DIR, hash, "json")
+}
+
+/// Where `source_file` is compressed to before its hash is known. It's named after the source
+/// so an interrupted large file is found again by the next attempt.
+pub fn incoming_path(source_file: &Path, destination_root: &Path) -> PathBuf {
+    let source_id = blake3::hash(source_file.as_os_str().as_encoded_bytes()).to_hex();
+    destination_root
+        .join(OBJECTS_DIR)
+        .join(format!("incoming-{}", &source_id[..16]))
+}
+
+/// Compress `source_file` into the object store under its BLAKE3 hash. The file is hashed
+/// while it is compressed, so the compressed copy is dropped if identical content turns out
+/// to be stored already.
+pub fn store_object(source_file: &Path, destination_root: &Path, durability: Durability) -> Result<StoredObject> {
+    let incoming = incoming_path(source_file, destination_root);
+    let (temp_file, hash) = compression::compress_to_temp(source_file, &incoming, durability)?;
+
+    let object_file = destination_root.join(object_path(&hash));
+    if object_file.exists() {
+        fs::remove_file(&temp_file)?;
+        return Ok(StoredObject {
+            hash,
+            newly_stored: false,
+        });
+    }
+
+    let renamed = match object_file.parent() {
+        Some(parent) => fs::create_dir_all(parent).map_err(anyhow::Error::from),
+        None => Ok(()),
+    }
+    .and_then(|_| compression::rename_into_place(&temp_file, &object_file, durability));
+    if let Err(e) = renamed {
+        let _ = fs::remove_file(&temp_file);
+        return Err(e);
+    }
+
+    Ok(StoredObject {
+        hash,
+        newly_stored: true,
+    })
+}
+
+/// Split `source_file` into content-defined chunks and store each chunk that isn't stored yet,
+/// followed by the file's chunk list under its BLAKE3 hash
+pub fn store_chunked(source_file: &Path, destination_root: &Path, durability: Durability) -> Result<StoredObject> {
+    let file = throttle::open(source_file)?;
+    let chunker = StreamCDC::new(file, CHUNK_MIN_SIZE, CHUNK_AVG_SIZE, CHUNK_MAX_SIZE);
+    let mut file_hasher = Hasher::new();
+    let mut chunks = Vec::new();
+
+    for chunk in chunker {
+        let chunk = chunk.map_err(io::Error::from)?;
+        file_hasher.update(&chunk.data);
+
+        let hash = blake3::hash(&chunk.data).to_hex().to_string();
+        let chunk_file = destination_root.join(chunk_path(&hash));
+        if !chunk_file.exists() {
+            let compressed = compression::compress_bytes(&chunk.data)?;
+            compression::write_atomically(&chunk_file, &compressed, durability)?;
+        }
+
+        chunks.push(ChunkRef {
+            hash,
+            length: chunk.length as u64,
+        });
+    }
+
+    let hash = file_hasher.finalize().to_hex().to_string();
+    let chunk_list_file = destination_root.join(chunk_list_path(&hash));
+    if chunk_list_file.exists() {
+        return Ok(StoredObject {
+            hash,
+            newly_stored: false,
+        });
+    }
+
+    let content = serde_json::to_string(&ChunkList { chunks })?;
+    compression::write_atomically(&chunk_list_file, content.as_bytes(), durability)?;
+
+    Ok(StoredObject {
+        hash,
+        newly_stored: true,
+    })
+}
+
+/// Append a small file to the current pack file under its BLAKE3 hash, skipping it if
+/// identical content is already packed or stored as a loose object
+pub fn store_packed(source_file: &Path, destination_root: &Path, packs: &PackWriter) -> Result<StoredObject> {
+    let mut data = Vec::new();
+    throttle::open(source_file)?.read_to_end(&mut data)?;
+    let hash = blake3::hash(&data).to_hex().to_string();
+
+    if packs.contains(&hash) || destination_root.join(object_path(&hash)).exists() {
+        return Ok(StoredObject {
+            hash,
+            newly_stored: false,
+        });
+    }
+
+    let compressed = compression::compress_bytes(&data)?;
+    let newly_stored = packs.add(&hash, &compressed)?;
+    Ok(StoredObject { hash, newly_stored })
+}
+
+/// Write the original content of a chunked file to `writer`, using the chunk list at
+/// `chunk_list_file`
+pub fn read_chunked<W: Write>(destination_root: &Path, chunk_list_file: &Path, writer: &mut W) -> Result<()> {
+    let content = fs::read_to_string(chunk_list_file)?;
+    let chunk_list: ChunkList = serde_json::from_str(&content)?;
+
+    for chunk in &chunk_list.chunks {
+        let chunk_file = destination_root.join(chunk_path(&chunk.hash));
+        let compressed = fs::read(&chunk_file)
+            .with_context(|| format!("Missing chunk {}", chunk.hash))?;
+        let data = compression::decompress_bytes(&compressed)?;
+        if data.len() as u64 != chunk.length {
+            bail!("Chunk {} has the wrong length", chunk.hash);
+        }
+        writer.write_all(&data)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hashing::hash_file;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_object_path() {
+        assert_eq!(
+            object_path("abcdef"),
+            PathBuf::from(".mbbut/objects/ab/abcdef.zst")
+        );
+    }
+
+    #[test]
+    fn test_store_object() {
+        let source_dir = tempdir().unwrap();
+        let dest_dir = tempdir().unwrap();
+        let source_file = source_dir.path().join("file.txt");
+        fs::write(&source_file, b"Stored content").unwrap();
+
+        // The object is stored under its hash and decompresses to the original content
+        let stored = store_object(&source_file, dest_dir.path(), Durability::File).unwrap();
+        assert!(stored.newly_stored);
+        assert_eq!(stored.hash, hash_file(&source_file).unwrap());
+
+        let object_file = dest_dir.path().join(object_path(&stored.hash));
+        let decompressed_path = dest_dir.path().join("decompressed.txt");
+        compression::decompress_file(&object_file, &decompressed_path).unwrap();
+        assert_eq!(fs::read(&decompressed_path).unwrap(), b"Stored content");
+
+        // No temporary files are left behind
+        assert_eq!(count_files(dest_dir.path()), 2); // The object and the decompressed copy
+    }
+
+    #[test]
+    fn test_chunk_paths() {
+        assert_eq!(chunk_path("abcdef"), PathBuf::from(".mbbut/chunks/ab/abcdef.zst"));
+        assert_eq!(
+            chunk_list_path("abcdef"),
+            PathBuf::from(".mbbut/chunklists/ab/abcdef.json")
+        );
+    }
+
+    /// Deterministic pseudo-random bytes that don't compress or repeat
+    fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                (state >> 33) as u8
+            })
+            .collect()
+    }
+
+    fn count_files(dir: &Path) -> usize {
+        walkdir::WalkDir::new(dir)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file())
+            .count()
+    }
+
+    #[test]
+    fn test_store_and_restore_chunked() {
+        let source_dir = tempdir().unwrap();
+        let dest_dir = tempdir().unwrap();
+        let source_file = source_dir.path().join("disk.img");
+        let content = noise(6 * 1024 * 1024, 1);
+        fs::write(&source_file, &content).unwrap();
+
+        // The file hash matches a plain hash of the whole file
+        let stored = store_chunked(&source_file, dest_dir.path(), Durability::File).unwrap();
+        assert!(stored.newly_stored);
+        assert_eq!(stored.hash, hash_file(&source_file).unwrap());
+        assert!(count_files(&dest_dir.path().join(CHUNKS_DIR)) > 1);
+
+        // Reading from the chunk list rebuilds the original bytes
+        let chunk_list_file = dest_dir.path().join(chunk_list_path(&stored.hash));
+        let mut restored = Vec::new();
+        read_chunked(dest_dir.path(), &chunk_list_file, &mut restored).unwrap();
+        assert_eq!(restored, content);
+    }
+
+    #[test]
+    fn test_store_chunked_reuses_unchanged_chunks() {
+        let source_dir = tempdir().unwrap();
+        let dest_dir = tempdir().unwrap();
+        let source_file = source_dir.path().join("mailbox.pst");
+        let mut content = noise(8 * 1024 * 1024, 2);
+        fs::write(&source_file, &content).unwrap();
+        store_chunked(&source_file, dest_dir.path(), Durability::File).unwrap();
+        let chunks_before = count_files(&dest_dir.path().join(CHUNKS_DIR));
+
+        // Change a few bytes near the end of the file
+        let len = content.len();
+        content[len - 1000..len - 990].copy_from_slice(b"0123456789");
+        fs::write(&source_file, &content).unwrap();
+        let stored = store_chunked(&source_file, dest_dir.path(), Durability::File).unwrap();
+        assert!(stored.newly_stored);
+
+        // Only the chunks around the change are new
+        let new_chunks = count_files(&dest_dir.path().join(CHUNKS_DIR)) - chunks_before;
+        assert!((1..=2).contains(&new_chunks), "stored {} new chunks", new_chunks);
+    }
+
+    #[test]
+    fn test_store_object_deduplicates() {
+        let source_dir = tempdir().unwrap();
+        let dest_dir = tempdir().unwrap();
+        let first = source_dir.path().join("first.txt");
+        let copy = source_dir.path().join("copy.txt");
+        fs::write(&first, b"Same content").unwrap();
+        fs::write(&copy, b"Same content").unwrap();
+
+        let first_stored = store_object(&first, dest_dir.path(), Durability::File).unwrap();
+        let copy_stored = store_object(&copy, dest_dir.path(), Durability::File).unwrap();
+
+        // The copy maps to the existing object instead of being stored again
+        assert_eq!(first_stored.hash, copy_stored.hash);
+        assert!(first_stored.newly_stored);
+        assert!(!copy_stored.newly_stored);
+        assert_eq!(count_files(dest_dir.path()), 1);
+    }
+
+    #[test]
+    fn test_store_packed() {
+        let source_dir = tempdir().unwrap();
+        let dest_dir = tempdir().unwrap();
+        let packs = PackWriter::open(dest_dir.path(), 1024 * 1024, Durability::File).unwrap();
+        let first = source_dir.path().join("first.txt");
+        let copy = source_dir.path().join("copy.txt");
+        fs::write(&first, b"Small packed content").unwrap();
+        fs::write(&copy, b"Small packed content").unwrap();
+
+        let first_stored = store_packed(&first, dest_dir.path(), &packs).unwrap();
+        let copy_stored = store_packed(&copy, dest_dir.path(), &packs).unwrap();
+        assert!(first_stored.newly_stored);
+        assert!(!copy_stored.newly_stored);
+        assert_eq!(first_stored.hash, hash_file(&first).unwrap());
+
+        // The object is read straight out of the pack
+        packs.sync().unwrap();
+        let index = crate::pack::PackIndex::load(dest_dir.path()).unwrap();
+        let location = index.get(&first_stored.hash).unwrap();
+        let compressed = crate::pack::read_object(dest_dir.path(), location).unwrap();
+        assert_eq!(compression::decompress_bytes(&compressed).unwrap(), b"Small packed content");
+
+        // No loose object was written
+        assert!(!dest_dir.path().join(OBJECTS_DIR).exists());
+    }
+}
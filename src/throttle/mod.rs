@@ -0,0 +1,211 @@
+use crate::progress;
+use crate::report::Operation;
+use anyhow::Result;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::{Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Limits shared by every file read and written during a run, if one is set
+static READ_LIMIT: RwLock<Option<Throttle>> = RwLock::new(None);
+static WRITE_LIMIT: RwLock<Option<Throttle>> = RwLock::new(None);
+
+/// Token bucket that spreads transfers out to at most `bytes_per_second` across all threads
+#[derive(Debug)]
+pub struct Throttle {
+    bytes_per_second: u64,
+    /// Bytes that may be transferred right away, negative when threads are ahead of the
+    /// limit, and when that was last worked out
+    state: Mutex<(f64, Instant)>,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_second: u64) -> Self {
+        Self {
+            bytes_per_second: bytes_per_second.max(1),
+            state: Mutex::new((0.0, Instant::now())),
+        }
+    }
+
+    /// Account for `bytes` that were just read, sleeping as long as it takes to get back
+    /// under the limit
+    pub fn consume(&self, bytes: usize) {
+        let rate = self.bytes_per_second as f64;
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let (available, last_refill) = &mut *state;
+            let now = Instant::now();
+
+            // Idle time only builds up to one second's worth of reads
+            *available = (*available + now.duration_since(*last_refill).as_secs_f64() * rate).min(rate);
+            *last_refill = now;
+            *available -= bytes as f64;
+
+            if *available < 0.0 {
+                Duration::from_secs_f64(-*available / rate)
+            } else {
+                Duration::ZERO
+            }
+        };
+
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+    }
+}
+
+/// Limit how fast files are read, and separately how fast they are written, from now on. `None`
+/// lifts the limit.
+pub fn set_limit(bytes_per_second: Option<u64>) {
+    *READ_LIMIT.write().unwrap() = bytes_per_second.map(Throttle::new);
+    *WRITE_LIMIT.write().unwrap() = bytes_per_second.map(Throttle::new);
+}
+
+fn consume(limit: &RwLock<Option<Throttle>>, bytes: usize) {
+    if let Some(throttle) = limit.read().unwrap().as_ref() {
+        throttle.consume(bytes);
+    }
+}
+
+/// I/O error of a throttled reader or writer, remembering whether it happened while reading or
+/// writing. It's wrapped in an `io::Error` of the same kind, which `report::Operation::of`
+/// looks into.
+#[derive(Debug)]
+pub struct TransferError {
+    pub operation: Operation,
+    source: io::Error,
+}
+
+impl TransferError {
+    fn wrap(operation: Operation, source: io::Error) -> io::Error {
+        io::Error::new(source.kind(), TransferError { operation, source })
+    }
+
+    /// The error as it came from the operating system
+    pub fn io_error(&self) -> &io::Error {
+        &self.source
+    }
+}
+
+impl fmt::Display for TransferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.source.fmt(f)
+    }
+}
+
+impl std::error::Error for TransferError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.source.source()
+    }
+}
+
+/// Reader or writer that keeps to the read or write limit, if one is set, and counts towards
+/// the progress of the file being backed up
+#[derive(Debug)]
+pub struct Throttled<T> {
+    inner: T,
+}
+
+impl<T> Throttled<T> {
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<R: Read> Read for Throttled<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self
+            .inner
+            .read(buf)
+            .map_err(|e| TransferError::wrap(Operation::Read, e))?;
+        progress::record_read(count);
+        consume(&READ_LIMIT, count);
+        Ok(count)
+    }
+}
+
+impl<W: Write> Write for Throttled<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = self
+            .inner
+            .write(buf)
+            .map_err(|e| TransferError::wrap(Operation::Write, e))?;
+        progress::record_written(count);
+        consume(&WRITE_LIMIT, count);
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush().map_err(|e| TransferError::wrap(Operation::Write, e))
+    }
+}
+
+/// Open a file for reading within the read limit
+pub fn open<P: AsRef<Path>>(path: P) -> Result<Throttled<File>> {
+    let file = File::open(path).map_err(|e| TransferError::wrap(Operation::Read, e))?;
+    Ok(Throttled { inner: file })
+}
+
+/// Write to `inner` within the write limit
+pub fn writer<W: Write>(inner: W) -> Throttled<W> {
+    Throttled { inner }
+}
+
+/// Lower the CPU and I/O scheduling priority of the calling thread and the threads it starts
+/// afterwards, so a backup doesn't get in the way of other work
+#[cfg(target_os = "linux")]
+pub fn lower_priority() -> Result<()> {
+    // Linux has no libc wrapper for ioprio_set
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+
+    // Both calls only affect the calling thread, which threads started later inherit from
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, 19) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let priority = IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT;
+    if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, priority) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// Lowering the priority is only supported on Linux
+#[cfg(not(target_os = "linux"))]
+pub fn lower_priority() -> Result<()> {
+    anyhow::bail!("Low priority mode is only supported on Linux")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_throttle_limits_rate() {
+        let throttle = Throttle::new(1_000_000);
+        let start = Instant::now();
+
+        // Reading 300 KB at 1 MB/s takes about 0.3 seconds
+        for _ in 0..3 {
+            throttle.consume(100_000);
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(250), "took {:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(2), "took {:?}", elapsed);
+    }
+
+    #[test]
+    fn test_throttle_allows_small_reads_after_idle_time() {
+        let throttle = Throttle::new(1_000_000);
+        thread::sleep(Duration::from_millis(100));
+
+        // Time spent idle pays for reads that follow
+        let start = Instant::now();
+        throttle.consume(50_000);
+        assert!(start.elapsed() < Duration::from_millis(40));
+    }
+}